@@ -0,0 +1,57 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use peacock_pinion::xml::select::NodeSelector;
+use peacock_pinion::xml::{StoreEntryAsync, XmlStore};
+
+// the `static/xml` layout, nested `depth` levels with `width` rows per level
+fn layout(depth: usize, width: usize) -> String {
+    if depth == 0 {
+        return "<Button class=\"nav\"><Icon /></Button>".into();
+    }
+
+    let mut result = String::from("<Container><Column class=\"navbar\">");
+    for _ in 0..width {
+        result += &format!("<Row class=\"button-wrapper\">{}</Row>", layout(depth - 1, width));
+    }
+    result += "</Column></Container>";
+    result
+}
+
+fn entry() -> StoreEntryAsync {
+    let store = XmlStore::new();
+    let mut store_guard = store.write().unwrap();
+    store_guard.append_from_source("bench".into(), layout(5, 5)).unwrap()
+}
+
+fn traversal(c: &mut Criterion) {
+    let entry = entry();
+    let root = entry.read().unwrap().nodes[0].clone();
+    let document = entry.read().unwrap().to_document();
+    let document_root = document.roots()[0];
+
+    let mut group = c.benchmark_group("leaves");
+    group.bench_function("NodeAsync", |b| b.iter(|| black_box(root.get_leaves().len())));
+    group.bench_function("XmlDocument", |b| {
+        b.iter(|| black_box(document.get_leaves(document_root).len()))
+    });
+    group.finish();
+}
+
+fn selector(c: &mut Criterion) {
+    let entry = entry();
+    let root = entry.read().unwrap().nodes[0].clone();
+    let document = entry.read().unwrap().to_document();
+    let document_root = document.roots()[0];
+
+    let selector = NodeSelector::parse("Container > Column.navbar > Row + Row Button").unwrap();
+
+    let mut group = c.benchmark_group("select");
+    group.bench_function("NodeAsync", |b| b.iter(|| black_box(selector.apply(root.clone()).len())));
+    group.bench_function("XmlDocument", |b| {
+        b.iter(|| black_box(document.select(&selector, document_root).len()))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, traversal, selector);
+criterion_main!(benches);
@@ -1,74 +1,80 @@
-// use std::time::Duration;
-// use std::thread::sleep;
-// use std::ffi::OsStr;
+use std::path::Path;
+use std::thread::sleep;
+use std::time::Duration;
 
-// use peacock_pinion::template as Template;
-// use peacock_pinion::xml as Xml;
+use peacock_pinion::template as Template;
+use peacock_pinion::xml as Xml;
+use peacock_pinion::xml::select as Select;
 
 pub fn main() {
-    // let template_store = Template::Store::new();
-
-    // let index_template = template_store.append("index".into(), OsStr::new("static/xml/mrpacker.xml".into())).unwrap();
-    // template_store.append("home".into(), OsStr::new("static/xml/home.xml".into())).unwrap();
-
-    // {
-    //     let mut env_guard = template_store.env.write().unwrap();
-
-    //     env_guard.add_global("title", "Mr. Packer V0.1.0");
-
-    //     env_guard.add_filter("make_nav_button", |nav_page_name: &str| -> String {
-    //         format!("<button class=\"nav\" id=\"{}\" />", nav_page_name)
-    //     });
-
-    //     env_guard.add_filter("make_special_button", |button_id: &str| -> String {
-    //         format!("<button class=\"special\" id=\"{}\" />", button_id)
-    //     });
-
-    //     let store_clone = template_store.clone();
-    //     env_guard.add_function("include_tree", move |index: String| -> String {
-    //         store_clone
-    //             .get(index)
-    //             .read()
-    //             .unwrap()
-    //             .source
-    //             .clone()
-    //     });
-    // }
-
-    // let dom_store = Xml::Store::new();
-
-    // let index_dom = dom_store.write().unwrap().append_from_template("index".into(), index_template).unwrap();
-
-    // {
-    //     let dom_guard = index_dom.read().unwrap();
-    //     println!("Found {} root elements!", dom_guard.nodes.len());
-    //     let first = (**dom_guard.nodes.get(0).unwrap()).read().unwrap().name.clone();
-    //     println!("First element is of type '{}'", first);
-    // }
-
-    // let row_selector = Select::NodeSelector::new()
-    //     .named("Container".into())
-    //         .child()
-    //         .named("Row".into())
-    //     .lock()
-    //     ;
-    // println!("Searching for {}", row_selector.read().unwrap().to_string());
-
-    // // loop for profiling purposes
-    // loop {
-    //     template_store.has("index".into());
-    //     dom_store.read().unwrap().has("index".into());
-
-    //     let mut selector_handle = row_selector.write().unwrap();
-
-    //     let mut row_count: u32 = 0;
-    //     for root in index_dom.read().unwrap().nodes.iter() {
-    //         for _ in selector_handle.apply(root.clone()).iter() {
-    //             row_count += 1;
-    //         }
-    //     }
-    //     println!("There are {} 'Container > Row' elements!", row_count);
-
-    //     sleep(Duration::from_secs(1));
-    // }
+    let template_store = Template::TemplateStore::new();
+
+    let index_template = {
+        let store_guard = template_store.read().unwrap();
+        // "index" includes "home", so "home" has to be in the store first
+        store_guard
+            .append_from_file("home".into(), Path::new("static/xml/home.xml"))
+            .unwrap();
+        store_guard
+            .append_from_file("index".into(), Path::new("static/xml/mrpacker.xml"))
+            .unwrap()
+    };
+
+    {
+        let store_guard = template_store.read().unwrap();
+        let mut env_guard = store_guard.env.write().unwrap();
+
+        env_guard.add_global("title", "Mr. Packer V0.1.0");
+
+        env_guard.add_filter("make_nav_button", |nav_page_name: &str| -> String {
+            format!("<button class=\"nav\" id=\"{}\" />", nav_page_name)
+        });
+
+        env_guard.add_filter("make_special_button", |button_id: &str| -> String {
+            format!("<button class=\"special\" id=\"{}\" />", button_id)
+        });
+
+        // `include_tree` is registered by the store itself
+    }
+
+    let dom_store = Xml::XmlStore::new();
+
+    let index_dom = dom_store
+        .write()
+        .unwrap()
+        .append_from_rendered_template("index".into(), index_template, minijinja::context! {})
+        .unwrap();
+
+    {
+        let dom_guard = index_dom.read().unwrap();
+        println!("Found {} root elements!", dom_guard.nodes.len());
+        let first = dom_guard.nodes.first().unwrap().try_read().unwrap().name.clone();
+        println!("First element is of type '{}'", first);
+    }
+
+    let row_selector = Select::NodeSelector::new()
+        .named("Container".into())
+            .child()
+            .named("Row".into())
+        .lock()
+        ;
+    println!("Searching for {}", row_selector.read().unwrap());
+
+    // loop for profiling purposes
+    loop {
+        template_store.read().unwrap().has(&"index".into()).unwrap();
+        dom_store.read().unwrap().has(&"index".into()).unwrap();
+
+        let selector_handle = row_selector.write().unwrap();
+
+        let mut row_count: u32 = 0;
+        for root in index_dom.read().unwrap().nodes.iter() {
+            for _ in selector_handle.apply(root.clone()).iter() {
+                row_count += 1;
+            }
+        }
+        println!("There are {} 'Container > Row' elements!", row_count);
+
+        sleep(Duration::from_secs(1));
+    }
 }
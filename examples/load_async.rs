@@ -0,0 +1,45 @@
+use std::path::Path;
+
+use peacock_pinion::template as Template;
+use peacock_pinion::xml as Xml;
+
+#[tokio::main]
+pub async fn main() {
+    let template_store = Template::TemplateStore::new();
+    let dom_store = Xml::XmlStore::new();
+
+    // "index" includes "home", which may not be registered yet when it is
+    template_store.read().unwrap().add_search_path("static/xml").unwrap();
+
+    // every read runs concurrently on the runtime
+    let (index_template, home_template, home_dom) = tokio::join!(
+        Template::TemplateStore::append_from_file_async(
+            &template_store,
+            "index".into(),
+            Path::new("static/xml/mrpacker.xml"),
+        ),
+        Template::TemplateStore::append_from_file_async(
+            &template_store,
+            "home".into(),
+            Path::new("static/xml/home.xml"),
+        ),
+        Xml::XmlStore::append_from_file_async(&dom_store, "home".into(), Path::new("static/xml/home.xml")),
+    );
+
+    println!("Loaded template with {} bytes", index_template.unwrap().read().unwrap().source.len());
+    println!("Loaded template with {} bytes", home_template.unwrap().read().unwrap().source.len());
+    println!("Parsed {} root elements", home_dom.unwrap().read().unwrap().nodes.len());
+
+    // the futures are `Send`, so they can be spawned onto worker threads too
+    let missing = tokio::spawn(async move {
+        Template::TemplateStore::append_from_file_async(
+            &template_store,
+            "missing".into(),
+            Path::new("static/xml/missing.xml"),
+        )
+        .await
+    })
+    .await
+    .unwrap();
+    println!("Loading a missing file fails: {}", missing.is_err());
+}
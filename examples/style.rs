@@ -0,0 +1,49 @@
+use std::path::Path;
+
+use peacock_pinion::style as Style;
+use peacock_pinion::xml as Xml;
+
+pub fn main() {
+    let mut stylesheet = Style::Stylesheet::from_file(Path::new("static/css/mrpacker-page.css")).unwrap();
+    stylesheet.rules.extend(
+        Style::Stylesheet::parse(
+            r#"
+            Row { padding: 4px }
+            .button-wrapper { color: gray }
+            .plain { color: initial }
+            #save { color: green }
+            Column > Row { padding: 8px !important }
+            "#,
+        )
+        .rules,
+    );
+
+    let dom_store = Xml::XmlStore::new();
+    let entry = dom_store
+        .write()
+        .unwrap()
+        .append_from_source(
+            "page".into(),
+            r#"<Container style="color: navy; font-size: 12px; margin: 2px">
+                <Column><Row class="button-wrapper" id="save" style="color: red" /><Row /><Row class="plain" /></Column>
+            </Container>"#
+                .into(),
+        )
+        .unwrap();
+
+    let root = entry.read().unwrap().nodes[0].clone();
+    let styles = stylesheet.compute_tree(&root);
+    for row in Xml::select::NodeSelector::parse("Row").unwrap().apply(root).iter() {
+        let id = row.try_read().unwrap().get_attribute("Default", "id").unwrap();
+        let properties: Vec<String> = styles[&row.node_id()].iter().map(|(x, y)| format!("{x}: {y}")).collect();
+        println!("{id}: {}", properties.join("; "));
+    }
+
+    // unsupported rules are skipped and reported, the rest still apply
+    let partial = Style::Stylesheet::parse("Row { color: red }\nRow >> Column { color: blue }");
+    println!("{} rule(s) kept", partial.rules.len());
+    for diagnostic in partial.diagnostics.iter() {
+        println!("{diagnostic}");
+        println!("{}", diagnostic.location.snippet().unwrap());
+    }
+}
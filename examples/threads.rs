@@ -0,0 +1,55 @@
+use std::thread;
+
+use peacock_pinion::template as Template;
+use peacock_pinion::xml as Xml;
+use peacock_pinion::xml::select as Select;
+
+const THREADS: usize = 8;
+
+pub fn main() {
+    let template_store = Template::TemplateStore::new();
+    let list_template = template_store
+        .read()
+        .unwrap()
+        .append_raw(
+            "list".into(),
+            "<Column>{% for i in range(count) %}<Row class=\"item\">{{ i }}</Row>{% endfor %}</Column>".into(),
+        )
+        .unwrap();
+
+    let dom_store = Xml::XmlStore::new();
+    let selector = Select::NodeSelector::parse("Column > Row.item").unwrap();
+
+    // render, parse and query from every thread at once against the same stores
+    let workers: Vec<thread::JoinHandle<usize>> = (0..THREADS)
+        .map(|worker| {
+            let list_template = list_template.clone();
+            let dom_store = dom_store.clone();
+            let selector = selector.clone();
+
+            thread::spawn(move || {
+                // render before taking the store's write lock, so only the
+                // parse and insertion are serialized
+                let source = list_template
+                    .read()
+                    .unwrap()
+                    .render(minijinja::context! { count => worker + 1 })
+                    .unwrap();
+                let entry = dom_store
+                    .write()
+                    .unwrap()
+                    .append_from_source(format!("list-{}", worker), source)
+                    .unwrap();
+
+                let root = entry.read().unwrap().nodes[0].clone();
+                selector.apply(root).len()
+            })
+        })
+        .collect();
+
+    for (worker, handle) in workers.into_iter().enumerate() {
+        let row_count = handle.join().unwrap();
+        assert_eq!(row_count, worker + 1);
+        println!("thread {} found {} rows", worker, row_count);
+    }
+}
@@ -0,0 +1,44 @@
+use std::path::Path;
+use std::thread::sleep;
+use std::time::Duration;
+
+use peacock_pinion::template as Template;
+use peacock_pinion::xml as Xml;
+
+// edit anything under `static/xml` while this runs
+pub fn main() {
+    let template_store = Template::TemplateStore::new();
+    let dom_store = Xml::XmlStore::new();
+
+    let report = template_store
+        .read()
+        .unwrap()
+        .load_dir(Path::new("static/xml"), &Template::LoadOptions::default())
+        .unwrap();
+    for file in report.iter() {
+        println!("Loaded '{}': {}", file.index, file.result.is_ok());
+    }
+
+    let home_template = template_store.read().unwrap().get(&"home".into()).unwrap();
+    dom_store
+        .write()
+        .unwrap()
+        .append_from_rendered_template("home".into(), home_template, minijinja::context! {})
+        .unwrap();
+
+    let _watcher = template_store
+        .read()
+        .unwrap()
+        .watch(Some(dom_store.clone()), |event| {
+            println!("Reloaded templates {:?}", event.templates);
+            println!("Rendered again {:?}", event.entries);
+            for (index, err) in event.failed.iter() {
+                println!("Failed '{}': {}", index, err);
+            }
+        })
+        .unwrap();
+
+    loop {
+        sleep(Duration::from_secs(1));
+    }
+}
@@ -1,9 +1,14 @@
+use std::path::PathBuf;
+
 use derive_more::From;
 
 pub type Result<T> = core::result::Result<T, Error>;
 
 #[derive(Debug, From)]
 pub enum Error {
+    #[from]
+    Style(crate::style::Error),
+
     #[from]
     Template(crate::template::Error),
 
@@ -14,16 +19,150 @@ pub enum Error {
     Generic(String),
 }
 
+/// Where in a template or XML source something went wrong. Lines and
+/// columns count from 1, columns in characters.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Location {
+    pub index: Option<String>,
+    pub path: Option<PathBuf>,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    /// The text of `line`, kept so `snippet` does not need the source.
+    pub line_text: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct SourceReadFailureContents {
+    pub location: Location,
+    pub error: std::io::Error,
+}
+
 impl Error {
     pub fn generic(val: impl std::fmt::Display) -> Self {
         Self::Generic(val.to_string())
     }
+
+    pub fn location(&self) -> Option<&Location> {
+        match self {
+            Self::Style(err) => err.location(),
+            Self::Template(err) => err.location(),
+            Self::Xml(err) => err.location(),
+            Self::Usage(_) | Self::Generic(_) => None,
+        }
+    }
+
+    /// The offending source line with a caret under the failing column,
+    /// when the error knows where it happened.
+    pub fn snippet(&self) -> Option<String> {
+        self.location().and_then(Location::snippet)
+    }
+}
+
+impl SourceReadFailureContents {
+    pub fn new(index: Option<String>, path: impl Into<PathBuf>, error: std::io::Error) -> Self {
+        Self {
+            location: Location::new(index, Some(path.into())),
+            error,
+        }
+    }
+}
+
+impl Location {
+    pub fn new(index: Option<String>, path: Option<PathBuf>) -> Self {
+        Self {
+            index,
+            path,
+            ..Default::default()
+        }
+    }
+
+    /// Points at line `line` and column `column` of `source`.
+    pub fn at(mut self, source: &str, line: usize, column: Option<usize>) -> Self {
+        self.line = Some(line);
+        self.column = column;
+        self.line_text = source.lines().nth(line.saturating_sub(1)).map(String::from);
+        self
+    }
+
+    /// Points at the character starting at byte `offset` of `source`.
+    pub fn at_offset(self, source: &str, offset: usize) -> Self {
+        let offset = offset.min(source.len());
+        let before = source.get(..offset).unwrap_or(source);
+        let line = before.matches('\n').count() + 1;
+        let line_start = before.rfind('\n').map(|x| x + 1).unwrap_or(0);
+        let column = before[line_start..].chars().count() + 1;
+        self.at(source, line, Some(column))
+    }
+
+    pub fn snippet(&self) -> Option<String> {
+        let line = self.line?;
+        let line_text = self.line_text.as_ref()?;
+
+        let gutter = line.to_string().len();
+        let mut result = format!("{:gutter$}--> {}\n", "", self);
+        result += &format!("{:gutter$} |\n", "");
+        result += &format!("{line} | {line_text}\n");
+        if let Some(column) = self.column {
+            // tabs keep their width so the caret lines up under them
+            let padding: String = line_text
+                .chars()
+                .take(column.saturating_sub(1))
+                .map(|x| if x == '\t' { '\t' } else { ' ' })
+                .collect();
+            result += &format!("{:gutter$} | {padding}^\n", "");
+        }
+        Some(result)
+    }
 }
 
 impl core::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{self:?}")
+        match self {
+            Self::Style(err) => write!(f, "{err}"),
+            Self::Template(err) => write!(f, "{err}"),
+            Self::Xml(err) => write!(f, "{err}"),
+            Self::Usage(message) | Self::Generic(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.path, &self.index) {
+            (Some(path), _) => write!(f, "{}", path.display())?,
+            (None, Some(index)) => write!(f, "'{index}'")?,
+            (None, None) => write!(f, "<source>")?,
+        }
+        if let Some(line) = self.line {
+            write!(f, ":{line}")?;
+            if let Some(column) = self.column {
+                write!(f, ":{column}")?;
+            }
+        }
+        Ok(())
     }
 }
 
-impl std::error::Error for Error {}
+impl std::fmt::Display for SourceReadFailureContents {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.location, self.error)
+    }
+}
+
+// transparent over the module errors, whose message it already displays
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Style(err) => std::error::Error::source(err),
+            Self::Template(err) => std::error::Error::source(err),
+            Self::Xml(err) => std::error::Error::source(err),
+            Self::Usage(_) | Self::Generic(_) => None,
+        }
+    }
+}
+
+impl std::error::Error for SourceReadFailureContents {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
@@ -1,8 +1,9 @@
 mod error;
+pub mod style;
 pub mod template;
 pub mod xml;
 
-pub use error::{Error, Result};
+pub use error::{Error, Location, Result};
 
 use std::sync::{Arc, RwLock};
 
@@ -10,3 +11,24 @@ type AsyncHandle<T> = Arc<RwLock<T>>;
 
 pub use template::TemplateStore;
 pub use xml::XmlStore;
+
+// every handle is meant to be shared across threads; fail the build if one
+// of them stops being `Send + Sync`
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    assert_send_sync::<TemplateStore<'static>>();
+    assert_send_sync::<template::StoreEntry<'static>>();
+    assert_send_sync::<template::StoreEntryAsync<'static>>();
+
+    assert_send_sync::<XmlStore>();
+    assert_send_sync::<xml::StoreEntry>();
+    assert_send_sync::<xml::StoreEntryAsync>();
+    assert_send_sync::<xml::NodeAsync>();
+    assert_send_sync::<xml::XmlDocument>();
+
+    assert_send_sync::<style::Stylesheet>();
+    assert_send_sync::<style::ComputedStyle>();
+
+    assert_send_sync::<Error>();
+};
@@ -0,0 +1,53 @@
+use derive_more::From;
+
+pub use crate::error::SourceReadFailureContents;
+use crate::error::Location;
+
+/// A stylesheet or `style` attribute that could not be parsed, located at the
+/// offending character.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseFailureContents {
+    pub location: Location,
+    pub failure_message: String,
+}
+
+#[derive(Debug, From)]
+pub enum Error {
+    SourceReadFailure(Box<SourceReadFailureContents>),
+    ParseFailure(Box<ParseFailureContents>),
+}
+
+impl Error {
+    pub fn location(&self) -> Option<&Location> {
+        match self {
+            Self::SourceReadFailure(contents) => Some(&contents.location),
+            Self::ParseFailure(contents) => Some(&contents.location),
+        }
+    }
+}
+
+impl std::fmt::Display for ParseFailureContents {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.location, self.failure_message)
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::SourceReadFailure(contents) => write!(f, "could not read stylesheet {contents}"),
+            Self::ParseFailure(contents) => write!(f, "could not parse CSS {contents}"),
+        }
+    }
+}
+
+impl std::error::Error for ParseFailureContents {}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::SourceReadFailure(contents) => Some(&contents.error),
+            Self::ParseFailure(_) => None,
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use super::ComputedStyle;
+
+/// Properties a node takes from its parent when nothing sets them on the node
+/// itself. Custom properties (`--name`) are inherited as well.
+pub const INHERITED: &[&str] = &[
+    "color",
+    "cursor",
+    "direction",
+    "font",
+    "font-family",
+    "font-size",
+    "font-style",
+    "font-variant",
+    "font-weight",
+    "letter-spacing",
+    "line-height",
+    "list-style",
+    "list-style-image",
+    "list-style-position",
+    "list-style-type",
+    "quotes",
+    "text-align",
+    "text-indent",
+    "text-transform",
+    "visibility",
+    "white-space",
+    "word-spacing",
+];
+
+pub fn is_inherited(property: &str) -> bool {
+    property.starts_with("--") || INHERITED.contains(&property)
+}
+
+/// Settles the CSS-wide keywords in `style` against `parent`, the computed
+/// style of the node's parent, and fills in inherited properties the node does
+/// not set. There is no table of initial values, so `initial` leaves the
+/// property unset, and keeps the parent's value from flowing in.
+pub(super) fn resolve(style: ComputedStyle, parent: Option<&ComputedStyle>) -> ComputedStyle {
+    let mut result = ComputedStyle::default();
+    let from_parent = |property: &str| parent.and_then(|x| x.declaration(property)).cloned();
+
+    for (property, declaration) in style.declarations.iter() {
+        let inherits = match declaration.value.to_ascii_lowercase().as_str() {
+            "inherit" => true,
+            "initial" => false,
+            "unset" => is_inherited(property),
+            _ => {
+                result.declarations.insert(property.clone(), declaration.clone());
+                continue;
+            }
+        };
+        if let Some(inherited) = from_parent(property).filter(|_| inherits) {
+            result.declarations.insert(property.clone(), inherited);
+        }
+    }
+
+    if let Some(parent) = parent {
+        for (property, declaration) in parent.declarations.iter() {
+            if is_inherited(property) && !style.declarations.contains_key(property) {
+                result.declarations.insert(property.clone(), declaration.clone());
+            }
+        }
+    }
+
+    result
+}
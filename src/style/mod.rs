@@ -0,0 +1,381 @@
+mod error;
+mod inherit;
+mod parse;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+use std::vec::Vec;
+
+use crate::xml::select::{CompoundSelector, NodeSelector, PseudoClass};
+use crate::xml::{NodeAsync, NodeId, XmlNode};
+use crate::Result;
+pub use error::{Error, ParseFailureContents, SourceReadFailureContents};
+pub use inherit::{is_inherited, INHERITED};
+
+/// `property: value`, with a trailing `!important` split off.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Declaration {
+    pub property: String,
+    pub value: String,
+    pub important: bool,
+}
+
+/// `selector, selector { declaration; declaration }`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    pub selectors: Vec<NodeSelector>,
+    pub declarations: Vec<Declaration>,
+}
+
+/// Rules in source order, which breaks ties between equally specific ones.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stylesheet {
+    pub rules: Vec<Rule>,
+    /// What was skipped while parsing, in source order: rules with a selector
+    /// that is not supported, declarations that do not parse, and stray
+    /// braces.
+    pub diagnostics: Vec<ParseFailureContents>,
+}
+
+/// How specific a selector is, compared ids first, then classes, attributes
+/// and pseudo-classes, then types.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Specificity {
+    pub ids: u32,
+    pub classes: u32,
+    pub types: u32,
+}
+
+/// Where a declaration stands in the cascade: importance, then specificity,
+/// then the rule's and the declaration's position in the stylesheet.
+type Rank = (bool, Specificity, usize, usize);
+
+/// The declaration that won the cascade for each property set on a node.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ComputedStyle {
+    declarations: BTreeMap<String, Declaration>,
+}
+
+impl Specificity {
+    pub fn of(selector: &NodeSelector) -> Self {
+        let mut result = Self::default();
+        for compound in selector.compounds().iter() {
+            result.add(compound);
+        }
+        result
+    }
+
+    fn add(&mut self, compound: &CompoundSelector) {
+        if compound.name.is_some() {
+            self.types += 1;
+        }
+        if compound.id.is_some() {
+            self.ids += 1;
+        }
+        self.classes += (compound.classes.len() + compound.attributes.len()) as u32;
+        for pseudo_class in compound.pseudo_classes.iter() {
+            match pseudo_class {
+                // `:not()` counts as whatever it contains
+                PseudoClass::Not(inner) => self.add(inner),
+                _ => self.classes += 1,
+            }
+        }
+    }
+}
+
+impl Stylesheet {
+    /// Never fails: whatever does not parse is skipped, as CSS does, and
+    /// listed in `diagnostics`.
+    pub fn parse(source: &str) -> Self {
+        parse::parse(source, None)
+    }
+
+    /// Fails only if `path` cannot be read; see `parse`.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(source) => Ok(parse::parse(&source, Some(path.into()))),
+            Err(err) => {
+                let contents = SourceReadFailureContents::new(None, path, err);
+                Err(Error::SourceReadFailure(contents.into()).into())
+            }
+        }
+    }
+
+    /// Runs the cascade for `node` over the stylesheet's rules alone: for
+    /// each property, an `!important` declaration beats a normal one, then
+    /// the more specific selector wins, then the later declaration. A rule
+    /// with several matching selectors counts as its most specific one.
+    pub fn cascade(&self, node: &NodeAsync) -> ComputedStyle {
+        let mut winners: BTreeMap<&str, (Rank, &Declaration)> = BTreeMap::new();
+
+        for (rule_position, rule) in self.rules.iter().enumerate() {
+            let specificity = match rule.selectors.iter().filter(|x| x.matches(node)).map(Specificity::of).max() {
+                Some(specificity) => specificity,
+                None => continue,
+            };
+
+            for (position, declaration) in rule.declarations.iter().enumerate() {
+                let rank: Rank = (declaration.important, specificity, rule_position, position);
+                let winner = winners.entry(&declaration.property).or_insert((rank, declaration));
+                if rank > winner.0 {
+                    *winner = (rank, declaration);
+                }
+            }
+        }
+
+        ComputedStyle {
+            declarations: winners
+                .into_iter()
+                .map(|(property, (_, declaration))| (property.to_string(), declaration.clone()))
+                .collect(),
+        }
+    }
+
+    /// The style `node` ends up with: the cascade, overridden by its `style`
+    /// attribute, with inherited properties taken from its ancestors.
+    /// Declarations in a `style` attribute that do not parse are skipped, as
+    /// CSS does; `XmlNode::inline_style` reports them.
+    pub fn compute(&self, node: &NodeAsync) -> ComputedStyle {
+        let parent = node.parent().map(|x| self.compute(&x));
+        self.compute_under(node, parent.as_ref())
+    }
+
+    /// The computed style of `root` and every element below it.
+    pub fn compute_tree(&self, root: &NodeAsync) -> HashMap<NodeId, ComputedStyle> {
+        let mut result: HashMap<NodeId, ComputedStyle> = HashMap::new();
+        let parent = root.parent().map(|x| self.compute(&x));
+        let mut stack: Vec<(NodeAsync, Option<ComputedStyle>)> = vec![(root.clone(), parent)];
+
+        while let Some((node, parent)) = stack.pop() {
+            let children = {
+                let node_guard = node.read();
+                if !node_guard.is_element() {
+                    continue;
+                }
+                node_guard.children.clone()
+            };
+            let style = self.compute_under(&node, parent.as_ref());
+            stack.extend(children.into_iter().map(|x| (x, Some(style.clone()))));
+            result.insert(node.node_id(), style);
+        }
+
+        result
+    }
+
+    fn compute_under(&self, node: &NodeAsync, parent: Option<&ComputedStyle>) -> ComputedStyle {
+        let mut result = self.cascade(node);
+
+        // inline declarations sit above every rule of the same importance
+        let inline = match node.read().get_attribute("Default", "style") {
+            Some(style) => parse::valid_declarations(&style),
+            None => Vec::new(),
+        };
+        for declaration in inline.into_iter() {
+            let overrides = match result.declarations.get(&declaration.property) {
+                Some(cascaded) => declaration.important || !cascaded.important,
+                None => true,
+            };
+            if overrides {
+                result.declarations.insert(declaration.property.clone(), declaration);
+            }
+        }
+
+        inherit::resolve(result, parent)
+    }
+}
+
+impl XmlNode {
+    /// The declarations in this node's `style` attribute, in order, or the
+    /// first that does not parse.
+    pub fn inline_style(&self) -> Result<Vec<Declaration>> {
+        match self.get_attribute("Default", "style") {
+            Some(style) => {
+                let id = self.get_attribute("Default", "id").unwrap_or_default();
+                parse::parse_declarations(&style, format!("style of #{id}"))
+            }
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+impl NodeAsync {
+    /// Shorthand for `Stylesheet::compute`.
+    pub fn computed_style(&self, stylesheet: &Stylesheet) -> ComputedStyle {
+        stylesheet.compute(self)
+    }
+}
+
+impl std::str::FromStr for Stylesheet {
+    type Err = std::convert::Infallible;
+
+    fn from_str(source: &str) -> core::result::Result<Self, Self::Err> {
+        Ok(Self::parse(source))
+    }
+}
+
+impl ComputedStyle {
+    pub fn get(&self, property: &str) -> Option<&str> {
+        self.declarations.get(property).map(|x| x.value.as_str())
+    }
+
+    pub fn declaration(&self, property: &str) -> Option<&Declaration> {
+        self.declarations.get(property)
+    }
+
+    /// Properties and their values, sorted by property.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.declarations.iter().map(|(property, x)| (property.as_str(), x.value.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.declarations.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xml::{StoreEntryAsync, XmlStore};
+
+    #[test]
+    fn malformed_inline_declarations_are_skipped() {
+        let store = XmlStore::new();
+        let source = r#"<Column style="color: red; margin 4px; width: {1}; font-size: 2px">
+            <Row style="color: blue;;"/>
+        </Column>"#;
+        let entry = store.write().unwrap().append_from_source("test".into(), source.into()).unwrap();
+        let column = entry.read().unwrap().nodes[0].clone();
+        let row = column.read().children.iter().find(|x| x.read().is_element()).unwrap().clone();
+
+        assert!(column.read().inline_style().is_err());
+        let stylesheet = Stylesheet::parse("Row { margin: 1px }");
+
+        let column_style = stylesheet.compute(&column);
+        assert_eq!(column_style.get("color"), Some("red"));
+        assert_eq!(column_style.get("font-size"), Some("2px"));
+        assert_eq!(column_style.get("margin"), None);
+        assert_eq!(column_style.get("width"), None);
+
+        let styles = stylesheet.compute_tree(&column);
+        let row_style = &styles[&row.node_id()];
+        assert_eq!(row_style.get("color"), Some("blue"));
+        assert_eq!(row_style.get("font-size"), Some("2px"));
+        assert_eq!(row_style.get("margin"), Some("1px"));
+    }
+
+    fn skipped(stylesheet: &Stylesheet) -> Vec<(usize, usize, &str)> {
+        let position = |x: &ParseFailureContents| (x.location.line.unwrap(), x.location.column.unwrap());
+        stylesheet
+            .diagnostics
+            .iter()
+            .map(|x| (position(x).0, position(x).1, x.failure_message.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn unsupported_rules_are_skipped_and_reported() {
+        let stylesheet = Stylesheet::parse("a:hover { color: red } Row { color: blue }\nb::before, Row { x: y }");
+        assert_eq!(stylesheet.rules.len(), 1);
+        assert_eq!(stylesheet.rules[0].selectors, [NodeSelector::parse("Row").unwrap()]);
+        assert_eq!(
+            skipped(&stylesheet),
+            [(1, 3, "unsupported pseudo-class ':hover'"), (2, 3, "expected identifier, found ':'")]
+        );
+    }
+
+    #[test]
+    fn bad_declarations_are_skipped_and_the_rest_kept() {
+        let stylesheet = Stylesheet::parse("Row { color: red; bogus; : x; width: 2px }");
+        let properties: Vec<&str> = stylesheet.rules[0].declarations.iter().map(|x| x.property.as_str()).collect();
+        assert_eq!(properties, ["color", "width"]);
+        assert_eq!(
+            skipped(&stylesheet),
+            [(1, 19, "expected ':' in declaration"), (1, 26, "expected a property name")]
+        );
+    }
+
+    #[test]
+    fn stray_braces_and_unterminated_blocks_are_recovered_from() {
+        let stylesheet = Stylesheet::parse("} @media print { Row { a: b } } Row { color: red } Column { width: 1px");
+        let properties: Vec<&str> =
+            stylesheet.rules.iter().flat_map(|x| x.declarations.iter()).map(|x| x.property.as_str()).collect();
+        assert_eq!(properties, ["color", "width"]);
+        assert_eq!(skipped(&stylesheet), [(1, 1, "unexpected '}'"), (1, 59, "unterminated block")]);
+
+        let stylesheet = Stylesheet::parse("Row { color: red; width: {1}; height: 2px }");
+        let properties: Vec<&str> = stylesheet.rules[0].declarations.iter().map(|x| x.property.as_str()).collect();
+        assert_eq!(properties, ["color", "height"]);
+        assert_eq!(skipped(&stylesheet), [(1, 26, "unexpected '{' in declarations")]);
+    }
+
+    /// The first `Row` in `source`, with the entry that keeps its ancestors
+    /// alive.
+    fn row(source: &str) -> (StoreEntryAsync, NodeAsync) {
+        let store = XmlStore::new();
+        let entry = store.write().unwrap().append_from_source("test".into(), source.into()).unwrap();
+        let root = entry.read().unwrap().nodes[0].clone();
+        let node = NodeSelector::parse("Row").unwrap().apply(root)[0].clone();
+        (entry, node)
+    }
+
+    #[test]
+    fn specificity_counts_ids_classes_and_types() {
+        let of = |x: &str| {
+            let specificity = Specificity::of(&NodeSelector::parse(x).unwrap());
+            (specificity.ids, specificity.classes, specificity.types)
+        };
+        assert_eq!(of("Column > Row"), (0, 0, 2));
+        assert_eq!(of("Row#save.primary[kind]:first-child"), (1, 3, 1));
+        assert_eq!(of("*"), (0, 0, 0));
+        // `:not()` counts as its argument, not as a pseudo-class
+        assert_eq!(of("Row:not(#save)"), (1, 0, 1));
+        assert_eq!(of("Row:not(.a.b)"), (0, 2, 1));
+        let of = |x: &str| Specificity::of(&NodeSelector::parse(x).unwrap());
+        assert!(of("#a") > of(".a.b.c"));
+    }
+
+    #[test]
+    fn importance_beats_specificity() {
+        let (_entry, node) = row(r#"<Column><Row id="save" class="primary"/></Column>"#);
+        let stylesheet = Stylesheet::parse(
+            "Row { color: red !important; width: 1px } #save.primary { color: blue; width: 2px !important }",
+        );
+        let style = stylesheet.cascade(&node);
+        assert_eq!(style.get("color"), Some("red"));
+        assert_eq!(style.get("width"), Some("2px"));
+        assert!(style.declaration("width").unwrap().important);
+    }
+
+    #[test]
+    fn equal_specificity_goes_to_the_later_declaration() {
+        let (_entry, node) = row(r#"<Column><Row class="a b"/></Column>"#);
+        let stylesheet = Stylesheet::parse(".a { color: red; color: green } .b { width: 1px } .a { width: 2px }");
+        let style = stylesheet.cascade(&node);
+        assert_eq!(style.get("color"), Some("green"));
+        assert_eq!(style.get("width"), Some("2px"));
+
+        let stylesheet = Stylesheet::parse("Column > Row { color: red } Row { color: blue }");
+        assert_eq!(stylesheet.cascade(&node).get("color"), Some("red"));
+    }
+
+    #[test]
+    fn not_counts_as_its_argument_in_the_cascade() {
+        let (_entry, node) = row(r#"<Column><Row class="a"/></Column>"#);
+        // `Row:not(#other)` outranks `Row.a` by its id
+        let stylesheet = Stylesheet::parse("Row:not(#other) { color: red } Row.a { color: blue }");
+        assert_eq!(stylesheet.cascade(&node).get("color"), Some("red"));
+    }
+
+    #[test]
+    fn a_rule_counts_as_its_most_specific_matching_selector() {
+        let (_entry, node) = row(r#"<Column><Row id="save"/></Column>"#);
+        let stylesheet =
+            Stylesheet::parse("Row, #save, #missing.x.y { color: red } Row.x, Column > Row { color: blue }");
+        assert_eq!(stylesheet.cascade(&node).get("color"), Some("red"));
+
+        // a selector that does not match does not lend its weight
+        let stylesheet = Stylesheet::parse("Row, #missing { color: red } Column > Row { color: blue }");
+        assert_eq!(stylesheet.cascade(&node).get("color"), Some("blue"));
+    }
+}
@@ -0,0 +1,308 @@
+use std::path::PathBuf;
+
+use super::{Declaration, Error, ParseFailureContents, Rule, Stylesheet};
+use crate::error::Location;
+use crate::xml::select::NodeSelector;
+
+struct Parser<'s> {
+    source: &'s str,
+    /// `source` with comments blanked out byte for byte, so offsets into it
+    /// are offsets into `source`.
+    text: String,
+    position: usize,
+    /// Where `source` came from, without a line or column yet.
+    origin: Location,
+    /// What was skipped so far, in source order.
+    diagnostics: Vec<ParseFailureContents>,
+}
+
+impl<'s> Parser<'s> {
+    fn new(source: &'s str, origin: Location) -> Self {
+        Self {
+            source,
+            text: blank_comments(source),
+            position: 0,
+            origin,
+            diagnostics: Vec::new(),
+        }
+    }
+
+    fn failure(&self, offset: usize, failure_message: impl Into<String>) -> Box<ParseFailureContents> {
+        let contents = ParseFailureContents {
+            location: self.origin.clone().at_offset(self.source, offset),
+            failure_message: failure_message.into(),
+        };
+        contents.into()
+    }
+
+    /// Records that something at `offset` was skipped.
+    fn skip(&mut self, offset: usize, failure_message: impl Into<String>) {
+        let failure = self.failure(offset, failure_message);
+        self.diagnostics.push(*failure);
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = &self.text[self.position..];
+        self.position += rest.len() - rest.trim_start().len();
+    }
+
+    /// Reads every rule, skipping what does not parse the way CSS does: a
+    /// rule with a selector that does not parse goes whole, a declaration
+    /// that does not parse goes alone.
+    fn stylesheet(&mut self) -> Stylesheet {
+        let mut result = Stylesheet::default();
+        loop {
+            self.skip_whitespace();
+            match self.text[self.position..].chars().next() {
+                None => break,
+                Some('}') => {
+                    self.skip(self.position, "unexpected '}'");
+                    self.position += 1;
+                }
+                Some('@') => self.at_rule(),
+                Some(_) => result.rules.extend(self.rule()),
+            }
+        }
+        result.diagnostics = std::mem::take(&mut self.diagnostics);
+        result
+    }
+
+    /// At-rules are not supported; they are skipped whole, block and all.
+    fn at_rule(&mut self) {
+        let start = self.position;
+        match find_outside(&self.text[start..], &['{', ';', '}']) {
+            Some((offset, ';')) => self.position = start + offset + 1,
+            Some((offset, '{')) => {
+                let close = self.block_close(start + offset);
+                self.position = (close + 1).min(self.text.len());
+            }
+            Some((offset, _)) => {
+                self.skip(start + offset, "unexpected '}'");
+                self.position = start + offset + 1;
+            }
+            None => {
+                self.skip(start, "unterminated at-rule");
+                self.position = self.text.len();
+            }
+        }
+    }
+
+    /// The offset of the `}` closing the block opened at `open`, or the end
+    /// of the text if it is never closed.
+    fn block_close(&mut self, open: usize) -> usize {
+        let mut depth = 1;
+        let mut position = open + 1;
+        loop {
+            match find_outside(&self.text[position..], &['{', '}']) {
+                Some((offset, '{')) => {
+                    depth += 1;
+                    position += offset + 1;
+                }
+                Some((offset, _)) if depth == 1 => return position + offset,
+                Some((offset, _)) => {
+                    depth -= 1;
+                    position += offset + 1;
+                }
+                None => {
+                    self.skip(open, "unterminated block");
+                    return self.text.len();
+                }
+            }
+        }
+    }
+
+    fn rule(&mut self) -> Option<Rule> {
+        let start = self.position;
+        let open = match find_outside(&self.text[start..], &['{', '}', ';']) {
+            Some((offset, '{')) => start + offset,
+            Some((offset, found)) => {
+                self.skip(start + offset, format!("expected '{{', found '{found}'"));
+                self.position = start + offset + 1;
+                return None;
+            }
+            None => {
+                self.skip(self.text.len(), "expected '{', found end of stylesheet");
+                self.position = self.text.len();
+                return None;
+            }
+        };
+        let close = self.block_close(open);
+        self.position = (close + 1).min(self.text.len());
+
+        let selectors = match self.selectors(start, open) {
+            Ok(selectors) => selectors,
+            Err(failure) => {
+                self.diagnostics.push(*failure);
+                return None;
+            }
+        };
+        let mut declarations: Vec<Declaration> = Vec::new();
+        for (offset, piece) in split_outside(&self.text[open + 1..close], ';') {
+            match self.declaration(open + 1 + offset, piece) {
+                Ok(declaration) => declarations.extend(declaration),
+                Err(failure) => self.diagnostics.push(*failure),
+            }
+        }
+
+        Some(Rule {
+            selectors,
+            declarations,
+        })
+    }
+
+    fn selectors(&self, start: usize, end: usize) -> Result<Vec<NodeSelector>, Box<ParseFailureContents>> {
+        let mut result: Vec<NodeSelector> = Vec::new();
+        for (offset, piece) in split_outside(&self.text[start..end], ',') {
+            let trimmed = piece.trim();
+            let offset = start + offset + (piece.len() - piece.trim_start().len());
+            match NodeSelector::parse(trimmed) {
+                Ok(selector) => result.push(selector),
+                Err(crate::xml::Error::SelectorParseFailure(contents)) => {
+                    let column: usize = trimmed.chars().take(contents.column - 1).map(char::len_utf8).sum();
+                    return Err(self.failure(offset + column, contents.failure_message));
+                }
+                Err(err) => return Err(self.failure(offset, err.to_string())),
+            }
+        }
+        Ok(result)
+    }
+
+    /// `piece` starts at byte `offset`. Blank declarations and those without
+    /// a value are dropped, as CSS ignores them.
+    fn declaration(&self, offset: usize, piece: &str) -> Result<Option<Declaration>, Box<ParseFailureContents>> {
+        if piece.trim().is_empty() {
+            return Ok(None);
+        }
+        let offset = offset + (piece.len() - piece.trim_start().len());
+        let piece = piece.trim();
+        if let Some((brace, found)) = find_outside(piece, &['{', '}']) {
+            return Err(self.failure(offset + brace, format!("unexpected '{found}' in declarations")));
+        }
+
+        let (property, value) = match find_outside(piece, &[':']) {
+            Some((colon, _)) => (piece[..colon].trim(), piece[colon + 1..].trim()),
+            None => return Err(self.failure(offset, "expected ':' in declaration")),
+        };
+        if property.is_empty() || property.contains(char::is_whitespace) {
+            return Err(self.failure(offset, "expected a property name"));
+        }
+
+        let (value, important) = match value.rfind('!') {
+            Some(bang) if value[bang + 1..].trim().eq_ignore_ascii_case("important") => {
+                (value[..bang].trim_end(), true)
+            }
+            _ => (value, false),
+        };
+        if value.is_empty() {
+            return Ok(None);
+        }
+
+        // custom properties are case-sensitive, the rest are not
+        let property = if property.starts_with("--") {
+            property.to_string()
+        } else {
+            property.to_ascii_lowercase()
+        };
+        Ok(Some(Declaration {
+            property,
+            value: value.into(),
+            important,
+        }))
+    }
+}
+
+/// The first of `targets` in `text` that is outside strings, parentheses
+/// and brackets, with its byte offset.
+fn find_outside(text: &str, targets: &[char]) -> Option<(usize, char)> {
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    let mut depth = 0usize;
+    for (position, x) in text.char_indices() {
+        match quote {
+            Some(_) if escaped => escaped = false,
+            Some(_) if x == '\\' => escaped = true,
+            Some(open) if x == open => quote = None,
+            Some(_) => {}
+            None if x == '"' || x == '\'' => quote = Some(x),
+            None if depth == 0 && targets.contains(&x) => return Some((position, x)),
+            None if x == '(' || x == '[' => depth += 1,
+            None if x == ')' || x == ']' => depth = depth.saturating_sub(1),
+            None => {}
+        }
+    }
+    None
+}
+
+/// `text` split on `separator` wherever `find_outside` would find it, each
+/// piece with its byte offset.
+fn split_outside(text: &str, separator: char) -> Vec<(usize, &str)> {
+    let mut result: Vec<(usize, &str)> = Vec::new();
+    let mut start = 0;
+    while let Some((offset, _)) = find_outside(&text[start..], &[separator]) {
+        result.push((start, &text[start..start + offset]));
+        start += offset + separator.len_utf8();
+    }
+    result.push((start, &text[start..]));
+    result
+}
+
+fn blank_comments(source: &str) -> String {
+    let mut result = String::with_capacity(source.len());
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    let mut comment_end = 0;
+
+    for (position, x) in source.char_indices() {
+        if position < comment_end {
+            result.push_str(&" ".repeat(x.len_utf8()));
+            continue;
+        }
+        match quote {
+            Some(_) if escaped => escaped = false,
+            Some(_) if x == '\\' => escaped = true,
+            Some(open) if x == open => quote = None,
+            Some(_) => {}
+            None if x == '"' || x == '\'' => quote = Some(x),
+            None if source[position..].starts_with("/*") => {
+                comment_end = match source[position + 2..].find("*/") {
+                    Some(end) => position + 2 + end + 2,
+                    None => source.len(),
+                };
+                result.push(' ');
+                continue;
+            }
+            None => {}
+        }
+        result.push(x);
+    }
+    result
+}
+
+pub(super) fn parse(source: &str, path: Option<PathBuf>) -> Stylesheet {
+    Parser::new(source, Location::new(None, path)).stylesheet()
+}
+
+/// Parses the body of a declaration block on its own, as found in a `style`
+/// attribute, failing on the first declaration that does not parse. `index`
+/// names the attribute in failures.
+pub(super) fn parse_declarations(source: &str, index: String) -> crate::Result<Vec<Declaration>> {
+    let parser = Parser::new(source, Location::new(Some(index), None));
+    let mut result: Vec<Declaration> = Vec::new();
+    for (offset, piece) in split_outside(&parser.text, ';') {
+        match parser.declaration(offset, piece) {
+            Ok(declaration) => result.extend(declaration),
+            Err(failure) => return Err(Error::ParseFailure(failure).into()),
+        }
+    }
+    Ok(result)
+}
+
+/// Like `parse_declarations`, but drops each declaration that does not parse
+/// and keeps the rest, the way CSS treats invalid declarations.
+pub(super) fn valid_declarations(source: &str) -> Vec<Declaration> {
+    let parser = Parser::new(source, Location::default());
+    split_outside(&parser.text, ';')
+        .into_iter()
+        .filter_map(|(offset, piece)| parser.declaration(offset, piece).ok().flatten())
+        .collect()
+}
@@ -0,0 +1,246 @@
+use std::collections::{BTreeSet, HashMap};
+use std::vec::Vec;
+
+use super::{StoreEntryAsync, StoreIndex};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DependencyKind {
+    Extends,
+    Include,
+    /// `{% import %}` and `{% from ... import %}`.
+    Import,
+    IncludeTree,
+}
+
+/// A reference from one template to another, found in its source.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Dependency {
+    pub index: StoreIndex,
+    pub kind: DependencyKind,
+    /// `{% include ... ignore missing %}` renders fine without its target.
+    pub optional: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Identifier(String),
+    Str(String),
+    Punctuation(char),
+}
+
+/// The references in `source` whose target is a string literal. References
+/// computed at render time cannot be known up front and are left out.
+pub(super) fn scan(source: &str) -> Vec<Dependency> {
+    let mut result: Vec<Dependency> = Vec::new();
+    let mut rest = source;
+
+    while let Some(start) = rest.find('{') {
+        rest = &rest[start..];
+        let (close, is_statement) = match rest.get(..2) {
+            Some("{%") => ("%}", true),
+            Some("{{") => ("}}", false),
+            Some("{#") => {
+                rest = rest.find("#}").map(|x| &rest[x + 2..]).unwrap_or_default();
+                continue;
+            }
+            _ => {
+                rest = &rest[1..];
+                continue;
+            }
+        };
+
+        let body = &rest[2..];
+        let end = find_outside_strings(body, close).unwrap_or(body.len());
+        let tokens = tokenize(body[..end].trim_matches(['-', '+', '~']));
+        rest = body.get(end + 2..).unwrap_or_default();
+
+        if is_statement {
+            if tokens.first() == Some(&Token::Identifier("raw".into())) {
+                rest = skip_raw(rest);
+                continue;
+            }
+            scan_statement(&tokens, &mut result);
+        }
+        scan_include_tree(&tokens, &mut result);
+    }
+
+    result.sort();
+    result.dedup();
+    result
+}
+
+fn scan_statement(tokens: &[Token], result: &mut Vec<Dependency>) {
+    let kind = match tokens.first() {
+        Some(Token::Identifier(keyword)) => match keyword.as_str() {
+            "extends" => DependencyKind::Extends,
+            "include" => DependencyKind::Include,
+            "import" | "from" => DependencyKind::Import,
+            _ => return,
+        },
+        _ => return,
+    };
+
+    let optional = kind == DependencyKind::Include
+        && tokens
+            .windows(2)
+            .any(|x| x[0] == Token::Identifier("ignore".into()) && x[1] == Token::Identifier("missing".into()));
+
+    let mut targets: Vec<String> = Vec::new();
+    match tokens.get(1) {
+        Some(Token::Str(target)) => targets.push(target.clone()),
+        // `{% include ["first", "fallback"] %}` uses the first that exists
+        Some(Token::Punctuation('[')) if kind == DependencyKind::Include => {
+            for token in tokens[2..].iter() {
+                match token {
+                    Token::Str(target) => targets.push(target.clone()),
+                    Token::Punctuation(',') => {}
+                    _ => break,
+                }
+            }
+        }
+        _ => {}
+    }
+
+    let optional = optional || targets.len() > 1;
+    for target in targets.into_iter() {
+        result.push(Dependency {
+            index: target,
+            kind,
+            optional,
+        });
+    }
+}
+
+fn scan_include_tree(tokens: &[Token], result: &mut Vec<Dependency>) {
+    for window in tokens.windows(3) {
+        if let [Token::Identifier(name), Token::Punctuation('('), Token::Str(target)] = window {
+            if name == "include_tree" {
+                result.push(Dependency {
+                    index: target.clone(),
+                    kind: DependencyKind::IncludeTree,
+                    optional: false,
+                });
+            }
+        }
+    }
+}
+
+fn find_outside_strings(text: &str, needle: &str) -> Option<usize> {
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    for (position, character) in text.char_indices() {
+        match quote {
+            Some(_) if escaped => escaped = false,
+            Some(_) if character == '\\' => escaped = true,
+            Some(open) if character == open => quote = None,
+            Some(_) => {}
+            None if character == '"' || character == '\'' => quote = Some(character),
+            None if text[position..].starts_with(needle) => return Some(position),
+            None => {}
+        }
+    }
+    None
+}
+
+/// Whatever follows the `{% endraw %}` closing a raw block.
+fn skip_raw(text: &str) -> &str {
+    let mut rest = text;
+    while let Some(start) = rest.find("{%") {
+        let body = &rest[start + 2..];
+        let end = match body.find("%}") {
+            Some(end) => end,
+            None => return "",
+        };
+        rest = &body[end + 2..];
+        if body[..end].trim_matches(['-', '+', '~']).trim() == "endraw" {
+            return rest;
+        }
+    }
+    ""
+}
+
+fn tokenize(text: &str) -> Vec<Token> {
+    let mut result: Vec<Token> = Vec::new();
+    let mut characters = text.char_indices().peekable();
+
+    while let Some((start, character)) = characters.next() {
+        if character.is_whitespace() {
+            continue;
+        }
+
+        if character == '"' || character == '\'' {
+            let mut value = String::new();
+            let mut escaped = false;
+            for (_, next) in characters.by_ref() {
+                match next {
+                    _ if escaped => {
+                        value.push(next);
+                        escaped = false;
+                    }
+                    '\\' => escaped = true,
+                    _ if next == character => break,
+                    _ => value.push(next),
+                }
+            }
+            result.push(Token::Str(value));
+        } else if character.is_alphanumeric() || character == '_' {
+            let mut end = start + character.len_utf8();
+            while let Some((position, next)) = characters.peek() {
+                if !(next.is_alphanumeric() || *next == '_') {
+                    break;
+                }
+                end = position + next.len_utf8();
+                characters.next();
+            }
+            result.push(Token::Identifier(text[start..end].into()));
+        } else {
+            result.push(Token::Punctuation(character));
+        }
+    }
+
+    result
+}
+
+/// A path of indices from `from` back to `to` through `dependencies`, if
+/// `from` depends on `to` directly or indirectly. `lookup` gives each index's
+/// dependencies.
+pub(super) fn find_path(
+    from: &StoreIndex,
+    to: &StoreIndex,
+    lookup: &dyn Fn(&StoreIndex) -> Vec<StoreIndex>,
+) -> Option<Vec<StoreIndex>> {
+    let mut visited: BTreeSet<StoreIndex> = BTreeSet::new();
+    let mut previous: HashMap<StoreIndex, StoreIndex> = HashMap::new();
+    let mut pending: Vec<StoreIndex> = vec![from.clone()];
+
+    while let Some(current) = pending.pop() {
+        if &current == to {
+            let mut path = vec![current.clone()];
+            let mut cursor = &current;
+            while let Some(before) = previous.get(cursor) {
+                path.push(before.clone());
+                cursor = before;
+            }
+            path.reverse();
+            return Some(path);
+        }
+        if !visited.insert(current.clone()) {
+            continue;
+        }
+        for next in lookup(&current).into_iter() {
+            if !visited.contains(&next) {
+                previous.entry(next.clone()).or_insert_with(|| current.clone());
+                pending.push(next);
+            }
+        }
+    }
+    None
+}
+
+/// The registered dependencies of `entry`, or none if its lock is poisoned.
+pub(super) fn indices_of(entry: &StoreEntryAsync<'_>) -> Vec<StoreIndex> {
+    match entry.read() {
+        Ok(entry_guard) => entry_guard.dependencies.iter().map(|x| x.index.clone()).collect(),
+        Err(_) => Vec::new(),
+    }
+}
@@ -1,22 +1,139 @@
 use derive_more::From;
 
 use super::StoreIndex;
+pub use crate::error::SourceReadFailureContents;
+use crate::error::Location;
+
+/// A template that failed to compile or render, located in the template
+/// minijinja reported, which may be one it included.
+#[derive(Debug)]
+pub struct TemplateFailureContents {
+    pub location: Location,
+    pub error: minijinja::Error,
+}
 
 #[derive(Debug, From)]
 pub enum Error {
     #[from]
     Native(minijinja::Error),
 
-    SourceReadFailure(std::ffi::OsString),
+    SourceReadFailure(Box<SourceReadFailureContents>),
     AlreadyInStore(StoreIndex),
+    MissingIndex(StoreIndex),
+    NotFromFile(StoreIndex),
+    /// `index` extends, includes or imports a template that is neither in
+    /// the store nor on a search path.
+    MissingDependency {
+        index: StoreIndex,
+        dependency: StoreIndex,
+    },
+    /// Indices that depend on each other in turn, starting and ending with
+    /// the one being added.
+    DependencyCycle(Vec<StoreIndex>),
+    /// `index` cannot be removed while the registered `dependents` need it.
+    HasDependents {
+        index: StoreIndex,
+        dependents: Vec<StoreIndex>,
+    },
+    /// The entry outlived the `TemplateStore` it was registered in.
+    StoreDropped(StoreIndex),
+    /// A thread panicked while holding one of the store's locks.
+    PoisonedLock,
+
+    CompileFailure(Box<TemplateFailureContents>),
+    RenderFailure(Box<TemplateFailureContents>),
+
+    #[cfg(feature = "notify")]
+    #[from]
+    Watch(notify::Error),
+}
+
+impl TemplateFailureContents {
+    /// Locates `error` in `source`, the source of the template it names, or
+    /// of template `index` when it names none.
+    pub fn new(
+        error: minijinja::Error,
+        index: &StoreIndex,
+        source: Option<&str>,
+        path: Option<std::path::PathBuf>,
+    ) -> Self {
+        let index = error.name().unwrap_or(index).to_string();
+        let mut location = Location::new(Some(index), path);
+        if let (Some(source), Some(line)) = (source, error.line()) {
+            location = match error.range() {
+                Some(range) => location.at_offset(source, range.start),
+                None => location.at(source, line, None),
+            };
+        }
+        Self { location, error }
+    }
+}
+
+impl Error {
+    pub fn location(&self) -> Option<&Location> {
+        match self {
+            Self::SourceReadFailure(contents) => Some(&contents.location),
+            Self::CompileFailure(contents) | Self::RenderFailure(contents) => Some(&contents.location),
+            _ => None,
+        }
+    }
+}
+
+impl<G> From<std::sync::PoisonError<G>> for Error {
+    fn from(_: std::sync::PoisonError<G>) -> Self {
+        Self::PoisonedLock
+    }
+}
 
-    RenderFailure(String),
+impl std::fmt::Display for TemplateFailureContents {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.error.detail() {
+            Some(detail) => write!(f, "{}: {}: {}", self.location, self.error.kind(), detail),
+            None => write!(f, "{}: {}", self.location, self.error.kind()),
+        }
+    }
 }
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{self:?}")
+        match self {
+            Self::Native(err) => write!(f, "{err}"),
+            Self::SourceReadFailure(contents) => write!(f, "could not read template {contents}"),
+            Self::AlreadyInStore(index) => write!(f, "template '{index}' is already in the store"),
+            Self::MissingIndex(index) => write!(f, "no template at index '{index}'"),
+            Self::NotFromFile(index) => write!(f, "template '{index}' was not read from a file"),
+            Self::MissingDependency { index, dependency } => {
+                write!(f, "template '{index}' depends on '{dependency}', which is not in the store")
+            }
+            Self::DependencyCycle(cycle) => write!(f, "templates depend on each other: {}", cycle.join(" -> ")),
+            Self::HasDependents { index, dependents } => {
+                write!(f, "template '{index}' is still needed by {}", dependents.join(", "))
+            }
+            Self::StoreDropped(index) => write!(f, "the store of template '{index}' was dropped"),
+            Self::PoisonedLock => write!(f, "a template store lock was poisoned"),
+            Self::CompileFailure(contents) => write!(f, "could not compile {contents}"),
+            Self::RenderFailure(contents) => write!(f, "could not render {contents}"),
+            #[cfg(feature = "notify")]
+            Self::Watch(err) => write!(f, "could not watch templates: {err}"),
+        }
     }
 }
 
-impl std::error::Error for Error {}
+impl std::error::Error for TemplateFailureContents {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Native(err) => Some(err),
+            Self::SourceReadFailure(contents) => Some(&contents.error),
+            Self::CompileFailure(contents) | Self::RenderFailure(contents) => Some(&contents.error),
+            #[cfg(feature = "notify")]
+            Self::Watch(err) => Some(err),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,438 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::PoisonError;
+use std::vec::Vec;
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+use super::deps;
+use super::{Error, SourceReadFailureContents, StoreEntryAsync, StoreIndex, TemplateStore};
+use crate::{AsyncHandle, Result};
+
+/// Extension tried after the bare index when resolving against a search path.
+const EXTENSION: &str = "xml";
+
+#[derive(Debug, Clone)]
+pub struct LoadOptions {
+    /// Drop the file extension from the index, so `pages/home.xml` is
+    /// registered as `pages/home`.
+    pub strip_extension: bool,
+    /// Globs matched against the path relative to the root. When empty every
+    /// file is included.
+    pub include: Vec<String>,
+    /// Globs matched against the path relative to the root, applied after
+    /// `include`.
+    pub exclude: Vec<String>,
+}
+
+impl Default for LoadOptions {
+    fn default() -> Self {
+        Self {
+            strip_extension: true,
+            include: Vec::new(),
+            exclude: Vec::new(),
+        }
+    }
+}
+
+/// The outcome of registering one file found by `TemplateStore::load_dir`.
+#[derive(Debug)]
+pub struct LoadedFile<'a> {
+    pub path: PathBuf,
+    pub index: StoreIndex,
+    pub result: Result<StoreEntryAsync<'a>>,
+}
+
+fn build_globs(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns.iter() {
+        match Glob::new(pattern) {
+            Ok(glob) => builder.add(glob),
+            Err(err) => return Err(crate::Error::Usage(err.to_string())),
+        };
+    }
+    builder.build().map_err(|err| crate::Error::Usage(err.to_string()))
+}
+
+/// `relative` with `/` separators on every platform, since indices are also
+/// what templates pass to `include` and `include_tree`.
+fn index_for(relative: &Path, options: &LoadOptions) -> StoreIndex {
+    let relative = if options.strip_extension {
+        relative.with_extension("")
+    } else {
+        relative.to_path_buf()
+    };
+    relative
+        .components()
+        .map(|x| x.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// The file `index` names in the first search path that has it, trying the
+/// index as written and then with `.xml` appended. Indices that would leave
+/// the search path never resolve.
+fn resolve(search_paths: &[PathBuf], index: &str) -> Option<PathBuf> {
+    let relative = Path::new(index);
+    if !relative.components().all(|x| matches!(x, Component::Normal(_))) {
+        return None;
+    }
+
+    for search_path in search_paths.iter() {
+        let path = search_path.join(relative);
+        if path.is_file() {
+            return Some(path);
+        }
+        let path = search_path.join(format!("{index}.{EXTENSION}"));
+        if path.is_file() {
+            return Some(path);
+        }
+    }
+    None
+}
+
+/// The loader installed on every store's environment. It sees the search
+/// paths as they are when a template is first asked for.
+pub(super) fn search_loader(
+    search_paths: AsyncHandle<Vec<PathBuf>>,
+) -> impl Fn(&str) -> core::result::Result<Option<String>, minijinja::Error> + Send + Sync + 'static {
+    move |index: &str| {
+        // a list of paths is valid whatever a panicking writer was doing, and
+        // the loader has no way to report a poisoned lock, so it reads through
+        let path = match resolve(&search_paths.read().unwrap_or_else(PoisonError::into_inner), index) {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        match fs::read_to_string(&path) {
+            Ok(source) => Ok(Some(source)),
+            Err(err) => Err(minijinja::Error::new(
+                minijinja::ErrorKind::InvalidOperation,
+                format!("could not read '{}'", path.display()),
+            )
+            .with_source(err)),
+        }
+    }
+}
+
+impl<'a> TemplateStore<'a> {
+    /// Adds a directory that unregistered indices are looked up in when they
+    /// are first rendered, included or fetched with `get`. Earlier search
+    /// paths win.
+    pub fn add_search_path(&self, path: impl Into<PathBuf>) -> Result<()> {
+        self.search_paths.write().map_err(Error::from)?.push(path.into());
+        Ok(())
+    }
+
+    pub(super) fn resolve(&self, index: &StoreIndex) -> Result<Option<PathBuf>> {
+        Ok(resolve(&self.search_paths.read().map_err(Error::from)?, index))
+    }
+
+    /// Registers an entry for a template found on a search path, checking
+    /// its dependencies like any other registration.
+    pub(super) fn load_lazily(&self, index: &StoreIndex) -> Result<StoreEntryAsync<'a>> {
+        let path = match self.resolve(index)? {
+            Some(path) => path,
+            None => return Err(Error::MissingIndex(index.clone()).into()),
+        };
+        let source = match fs::read_to_string(&path) {
+            Ok(source) => source,
+            Err(err) => {
+                let contents = SourceReadFailureContents::new(Some(index.clone()), path, err);
+                return Err(Error::SourceReadFailure(contents.into()).into());
+            }
+        };
+
+        match self.register(index.clone(), source, Some(path)) {
+            // another thread may have loaded it in the meantime
+            Err(crate::Error::Template(Error::AlreadyInStore(_))) => self.get_registered(index),
+            result => result,
+        }
+    }
+
+    /// The indices the file `index` resolves to on a search path depends on,
+    /// or none if it resolves to nothing readable.
+    pub(super) fn scan_search_paths(&self, index: &StoreIndex) -> Vec<StoreIndex> {
+        match self.resolve(index).ok().flatten().and_then(|x| fs::read_to_string(x).ok()) {
+            Some(source) => deps::scan(&source).into_iter().map(|x| x.index).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Registers every file below `root`, indexed by its path relative to
+    /// `root`. Files are registered after the files they depend on, and a
+    /// failure on one does not stop the rest; only bad glob patterns fail the
+    /// whole call. The report is in sorted path order.
+    pub fn load_dir(&self, root: &Path, options: &LoadOptions) -> Result<Vec<LoadedFile<'a>>> {
+        let include = build_globs(&options.include)?;
+        let exclude = build_globs(&options.exclude)?;
+
+        // what was found, its source once read, and its outcome once known
+        let mut found: Vec<(PathBuf, StoreIndex)> = Vec::new();
+        let mut sources: Vec<Option<String>> = Vec::new();
+        let mut results: Vec<Option<Result<StoreEntryAsync<'a>>>> = Vec::new();
+        for entry in walkdir::WalkDir::new(root).sort_by_file_name() {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    let path = err.path().unwrap_or(root).to_path_buf();
+                    let relative = path.strip_prefix(root).unwrap_or(&path);
+                    let index = index_for(relative, options);
+                    let contents = SourceReadFailureContents::new(Some(index.clone()), path.clone(), err.into());
+                    found.push((path, index));
+                    sources.push(None);
+                    results.push(Some(Err(Error::SourceReadFailure(contents.into()).into())));
+                    continue;
+                }
+            };
+            if !entry.file_type().is_file() {
+                continue;
+            }
+
+            let path = entry.into_path();
+            let relative = path.strip_prefix(root).unwrap();
+            if !include.is_empty() && !include.is_match(relative) {
+                continue;
+            }
+            if exclude.is_match(relative) {
+                continue;
+            }
+
+            let index = index_for(relative, options);
+            match fs::read_to_string(&path) {
+                Ok(source) => {
+                    sources.push(Some(source));
+                    results.push(None);
+                }
+                Err(err) => {
+                    let contents = SourceReadFailureContents::new(Some(index.clone()), path.clone(), err);
+                    sources.push(None);
+                    results.push(Some(Err(Error::SourceReadFailure(contents.into()).into())));
+                }
+            }
+            found.push((path, index));
+        }
+
+        // what each file read depends on among the files read
+        let batch: HashMap<StoreIndex, Vec<StoreIndex>> = found
+            .iter()
+            .zip(sources.iter())
+            .filter_map(|((_, index), source)| source.as_ref().map(|x| (index, x)))
+            .map(|(index, source)| {
+                let dependencies = deps::scan(source).into_iter().map(|x| x.index).collect();
+                (index.clone(), dependencies)
+            })
+            .collect();
+        let waits_on = |index: &StoreIndex| -> Vec<StoreIndex> {
+            batch[index]
+                .iter()
+                // a poisoned store fails the registration itself instead
+                .filter(|x| batch.contains_key(*x) && !self.is_registered(x).unwrap_or(false))
+                .cloned()
+                .collect()
+        };
+
+        // register whatever has nothing left to wait on until nothing does
+        loop {
+            let mut progressed = false;
+            for (position, (path, index)) in found.iter().enumerate() {
+                if sources[position].is_none() || !waits_on(index).is_empty() {
+                    continue;
+                }
+                let source = sources[position].take().unwrap();
+                results[position] = Some(self.register(index.clone(), source, Some(path.clone())));
+                progressed = true;
+            }
+            if !progressed {
+                break;
+            }
+        }
+
+        // the rest wait on a cycle, or on a file that failed
+        for (position, (path, index)) in found.iter().enumerate() {
+            let source = match sources[position].take() {
+                Some(source) => source,
+                None => continue,
+            };
+            let lookup = |x: &StoreIndex| batch.get(x).cloned().unwrap_or_default();
+            let cycle = batch[index]
+                .iter()
+                .find_map(|x| deps::find_path(x, index, &lookup))
+                .map(|x| [vec![index.clone()], x].concat());
+            results[position] = Some(match cycle {
+                Some(cycle) => Err(Error::DependencyCycle(cycle).into()),
+                None => self.register(index.clone(), source, Some(path.clone())),
+            });
+        }
+
+        Ok(found
+            .into_iter()
+            .zip(results)
+            .map(|((path, index), result)| LoadedFile {
+                path,
+                index,
+                result: result.unwrap(),
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory holding `files`, named after the test using it.
+    fn scratch(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("peacock-pinion-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        for (relative, source) in files.iter() {
+            let path = root.join(relative);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, source).unwrap();
+        }
+        root
+    }
+
+    #[test]
+    fn lazily_loaded_templates_are_checked() {
+        let root = scratch(
+            "lazy-checks",
+            &[
+                ("c.xml", "<C>{% include 'd' %}</C>"),
+                ("d.xml", "<D>{% include 'c' %}</D>"),
+                ("lonely.xml", "<L>{% include 'nowhere' %}</L>"),
+            ],
+        );
+        let store = TemplateStore::new();
+        let store_guard = store.read().unwrap();
+        store_guard.add_search_path(&root).unwrap();
+
+        match store_guard.get(&"c".into()) {
+            Err(crate::Error::Template(Error::DependencyCycle(cycle))) => assert_eq!(cycle, ["c", "d", "c"]),
+            other => panic!("expected a cycle, got {other:?}"),
+        }
+        assert!(matches!(
+            store_guard.get(&"lonely".into()),
+            Err(crate::Error::Template(Error::MissingDependency { .. }))
+        ));
+        assert!(!store_guard.is_registered(&"c".into()).unwrap());
+        assert!(!store_guard.is_registered(&"lonely".into()).unwrap());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    fn outcomes(report: &[LoadedFile]) -> Vec<(String, String)> {
+        let outcome = |x: &LoadedFile| match &x.result {
+            Ok(_) => "ok".to_string(),
+            Err(crate::Error::Template(Error::DependencyCycle(cycle))) => format!("cycle {}", cycle.join(" -> ")),
+            Err(crate::Error::Template(Error::MissingDependency { dependency, .. })) => format!("missing {dependency}"),
+            Err(crate::Error::Template(Error::CompileFailure(_))) => "compile".to_string(),
+            Err(err) => format!("{err}"),
+        };
+        report.iter().map(|x| (x.index.clone(), outcome(x))).collect()
+    }
+
+    #[test]
+    fn load_dir_registers_dependencies_first_and_reports_each_file() {
+        let root = scratch(
+            "load-dir",
+            &[
+                ("a.xml", "<A>{% include 'z' %}</A>"),
+                ("broken.xml", "<B>{% if %}</B>"),
+                ("loop/one.xml", "{% include 'loop/two' %}"),
+                ("loop/two.xml", "{% include 'loop/one' %}"),
+                ("needs.xml", "{% include 'nowhere' %}"),
+                ("z.xml", "<Z>{% include 'pages/nested' %}</Z>"),
+                ("pages/nested.xml", "<N/>"),
+            ],
+        );
+        let store = TemplateStore::new();
+        let store_guard = store.read().unwrap();
+        let report = store_guard.load_dir(&root, &LoadOptions::default()).unwrap();
+
+        let expected = [
+            ("a", "ok"),
+            ("broken", "compile"),
+            ("loop/one", "cycle loop/one -> loop/two -> loop/one"),
+            ("loop/two", "cycle loop/two -> loop/one -> loop/two"),
+            ("needs", "missing nowhere"),
+            ("pages/nested", "ok"),
+            ("z", "ok"),
+        ];
+        let expected: Vec<(String, String)> = expected.iter().map(|(x, y)| (x.to_string(), y.to_string())).collect();
+        assert_eq!(outcomes(&report), expected);
+        assert_eq!(report[0].path, root.join("a.xml"));
+
+        let page = store_guard.get(&"a".into()).unwrap();
+        assert_eq!(page.read().unwrap().render(minijinja::context! {}).unwrap(), "<A><Z><N/></Z></A>");
+        assert!(!store_guard.is_registered(&"broken".into()).unwrap());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn load_dir_filters_by_glob_and_keeps_extensions_when_asked() {
+        let root = scratch(
+            "load-dir-globs",
+            &[
+                ("keep.xml", "<K/>"),
+                ("notes.txt", "not a template"),
+                ("drafts/skip.xml", "<S/>"),
+                ("pages/home.xml", "<H/>"),
+            ],
+        );
+        let store = TemplateStore::new();
+        let store_guard = store.read().unwrap();
+        let options = LoadOptions {
+            strip_extension: false,
+            include: vec!["**/*.xml".into()],
+            exclude: vec!["drafts/**".into()],
+        };
+        let report = store_guard.load_dir(&root, &options).unwrap();
+
+        let indices: Vec<&str> = report.iter().map(|x| x.index.as_str()).collect();
+        assert_eq!(indices, ["keep.xml", "pages/home.xml"]);
+        assert!(report.iter().all(|x| x.result.is_ok()));
+        assert!(store_guard.is_registered(&"pages/home.xml".into()).unwrap());
+
+        let bad = LoadOptions {
+            include: vec!["[".into()],
+            ..Default::default()
+        };
+        assert!(matches!(store_guard.load_dir(&root, &bad), Err(crate::Error::Usage(_))));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn search_paths_resolve_templates_on_first_use() {
+        let first = scratch("search-first", &[("card.xml", "<Card>{% include 'parts/title' %}</Card>")]);
+        let second = scratch(
+            "search-second",
+            &[("card.xml", "<Other/>"), ("parts/title", "<Title/>"), ("secret.xml", "<Secret/>")],
+        );
+        let store = TemplateStore::new();
+        let store_guard = store.read().unwrap();
+        assert!(!store_guard.has(&"card".into()).unwrap());
+        store_guard.add_search_path(&first).unwrap();
+        store_guard.add_search_path(&second).unwrap();
+
+        assert!(store_guard.has(&"card".into()).unwrap());
+        assert!(!store_guard.is_registered(&"card".into()).unwrap());
+        assert!(!store_guard.has(&"missing".into()).unwrap());
+
+        // the earlier search path wins, and includes load from any of them
+        let card = store_guard.get(&"card".into()).unwrap();
+        assert_eq!(card.read().unwrap().path, Some(first.join("card.xml")));
+        assert_eq!(card.read().unwrap().render(minijinja::context! {}).unwrap(), "<Card><Title/></Card>");
+        assert!(store_guard.is_registered(&"card".into()).unwrap());
+
+        let escaping = format!("../{}/secret", second.file_name().unwrap().to_string_lossy());
+        for index in [escaping.as_str(), "/etc/hostname", "parts/../card", "./card"] {
+            assert!(!store_guard.has(&index.into()).unwrap(), "{index}");
+            assert!(
+                matches!(store_guard.get(&index.into()), Err(crate::Error::Template(Error::MissingIndex(_)))),
+                "{index}"
+            );
+        }
+        fs::remove_dir_all(first).unwrap();
+        fs::remove_dir_all(second).unwrap();
+    }
+}
@@ -1,20 +1,36 @@
+mod deps;
 mod error;
+mod load;
+#[cfg(feature = "notify")]
+mod watch;
 
-use std::cell::OnceCell;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
+use std::path::PathBuf;
 use std::sync::{Arc, RwLock, Weak};
 
 use crate::{AsyncHandle, Result};
-pub use error::Error;
+pub use deps::{Dependency, DependencyKind};
+pub use error::{Error, SourceReadFailureContents, TemplateFailureContents};
+pub use load::{LoadOptions, LoadedFile};
+#[cfg(feature = "notify")]
+pub use watch::{ReloadEvent, Watcher};
 
 type StoreIndex = String;
 
+/// Element that `include_tree` wraps its output in. `XmlStore` splices the
+/// wrapper's children into its parent when parsing.
+pub const INCLUDE_TREE: &str = "include-tree";
+
 #[derive(Debug)]
 pub struct StoreEntry<'a> {
     store: Weak<RwLock<TemplateStore<'a>>>,
     index: StoreIndex,
     pub source: String,
+    /// The file the entry was read from, for `TemplateStore::reload`.
+    pub path: Option<PathBuf>,
+    dependencies: Vec<Dependency>,
+    version: u64,
 }
 
 pub type StoreEntryAsync<'a> = AsyncHandle<StoreEntry<'a>>;
@@ -23,109 +39,446 @@ pub type StoreEntryAsync<'a> = AsyncHandle<StoreEntry<'a>>;
 pub struct TemplateStore<'a> {
     pub env: AsyncHandle<minijinja::Environment<'a>>,
     indices: AsyncHandle<HashMap<StoreIndex, StoreEntryAsync<'a>>>,
-    handle: OnceCell<Arc<RwLock<Self>>>,
+    search_paths: AsyncHandle<Vec<PathBuf>>,
+    /// Weak, so the store does not keep itself alive.
+    handle: Weak<RwLock<Self>>,
 }
 
 impl<'a> StoreEntry<'a> {
+    pub fn index(&self) -> &StoreIndex {
+        &self.index
+    }
+
+    /// The templates this one extends, includes or imports by name, as found
+    /// in its source when it was last compiled.
+    pub fn dependencies(&self) -> &[Dependency] {
+        &self.dependencies
+    }
+
+    /// Goes up by one every time the source is replaced or reloaded, and
+    /// when the entry is removed from its store.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn store(&self) -> Result<Arc<RwLock<TemplateStore<'a>>>> {
+        match self.store.upgrade() {
+            Some(store_handle) => Ok(store_handle),
+            None => Err(Error::StoreDropped(self.index.clone()).into()),
+        }
+    }
+
     pub fn render(&self, context: minijinja::Value) -> Result<String> {
-        let store_handle = self.store.upgrade().unwrap();
-        let store_guard = store_handle.read().unwrap();
-        let env_guard = store_guard.env.read().unwrap();
+        let store_handle = self.store()?;
+        let store_guard = store_handle.read().map_err(Error::from)?;
+        let env_guard = store_guard.env.read().map_err(Error::from)?;
         match env_guard.get_template(&self.index) {
             Ok(template) => match template.render(context) {
                 Ok(rendered) => Ok(rendered),
-                Err(err) => Err(Error::RenderFailure(err.to_string()).into()),
+                Err(err) => {
+                    let failing = err.name().unwrap_or(&self.index).to_string();
+                    let contents = if failing == self.index {
+                        TemplateFailureContents::new(err, &self.index, Some(&self.source), self.path.clone())
+                    } else {
+                        // the error came from a template this one included
+                        let source = env_guard.get_template(&failing).ok().map(|x| x.source().to_string());
+                        let path = store_guard.path_of(&failing);
+                        TemplateFailureContents::new(err, &failing, source.as_deref(), path)
+                    };
+                    Err(Error::RenderFailure(contents.into()).into())
+                }
             },
-            Err(_) => todo!(),
+            Err(err) if err.kind() == minijinja::ErrorKind::TemplateNotFound => {
+                Err(Error::MissingIndex(self.index.clone()).into())
+            }
+            Err(err) => Err(Error::Native(err).into()),
+        }
+    }
+}
+
+/// `{{ include_tree("index") }}`: renders another entry with the current
+/// context and wraps the output for `XmlStore` to splice in as real nodes.
+fn include_tree(state: &minijinja::State, index: String) -> core::result::Result<minijinja::Value, minijinja::Error> {
+    let template = state.env().get_template(&index)?;
+
+    let mut context: BTreeMap<String, minijinja::Value> = BTreeMap::new();
+    for name in state.known_variables() {
+        if let Some(value) = state.lookup(&name) {
+            context.insert(name.into_owned(), value);
         }
     }
+    let rendered = template.render(context)?;
+
+    // a declaration is only valid at the very start of a document
+    let mut body = rendered.trim_start();
+    if body.starts_with("<?xml") {
+        body = body.split_once("?>").map(|(_, rest)| rest).unwrap_or_default();
+    }
+
+    let index = index
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('"', "&quot;");
+    Ok(minijinja::Value::from_safe_string(format!(
+        "<{INCLUDE_TREE} index=\"{index}\">{body}</{INCLUDE_TREE}>"
+    )))
 }
 
 impl<'a> TemplateStore<'a> {
     pub fn new() -> Arc<RwLock<TemplateStore<'a>>> {
-        let store = Self {
-            env: Arc::new(RwLock::new(minijinja::Environment::new())),
-            #[allow(clippy::arc_with_non_send_sync)]
-            indices: Arc::new(RwLock::new(HashMap::new())),
-            handle: OnceCell::new(),
-        };
+        let search_paths: AsyncHandle<Vec<PathBuf>> = Arc::new(RwLock::new(Vec::new()));
 
-        #[allow(clippy::arc_with_non_send_sync)]
-        let arc: Arc<RwLock<Self>> = Arc::new(RwLock::new(store));
+        let mut env = minijinja::Environment::new();
+        env.add_function("include_tree", include_tree);
+        env.set_loader(load::search_loader(search_paths.clone()));
 
-        arc.write().unwrap().handle.set(arc.clone()).unwrap();
-        arc.clone()
+        Arc::new_cyclic(|handle| {
+            RwLock::new(Self {
+                env: Arc::new(RwLock::new(env)),
+                indices: Arc::new(RwLock::new(HashMap::new())),
+                search_paths,
+                handle: handle.clone(),
+            })
+        })
     }
 
-    pub fn get_handle(&self) -> Arc<RwLock<Self>> {
-        self.handle.get().unwrap().clone()
+    /// The handle `new` returned, or `None` once every copy of it is dropped.
+    pub fn get_handle(&self) -> Option<Arc<RwLock<Self>>> {
+        self.handle.upgrade()
     }
 
     pub fn append_raw(&self, index: StoreIndex, source: String) -> Result<StoreEntryAsync<'a>> {
-        if self.has(&index) {
-            Err(Error::AlreadyInStore(index.clone()).into())
-        } else {
-            #[allow(clippy::arc_with_non_send_sync)]
-            let entry: StoreEntryAsync<'a> = Arc::new(RwLock::new(StoreEntry {
-                store: Arc::downgrade(&self.get_handle()),
-                index: index.clone(),
-                source,
-            }));
+        self.register(index, source, None)
+    }
 
-            let mut store_guard = self.indices.write().unwrap();
-            store_guard.insert(index.clone(), entry.clone());
+    pub fn append_from_file(&self, index: StoreIndex, path: &std::path::Path) -> Result<StoreEntryAsync<'a>> {
+        if self.is_registered(&index)? {
+            return Err(Error::AlreadyInStore(index).into());
+        }
 
-            let mut env_guard = self.env.write().unwrap();
-            let result =
-                env_guard.add_template_owned(index, entry.read().unwrap().source.clone());
-            match result {
-                Ok(_) => Ok(entry),
-                Err(err) => Err(Error::Native(err).into()),
+        match fs::read_to_string(path) {
+            Ok(source) => self.register(index, source, Some(path.into())),
+            Err(err) => {
+                let contents = SourceReadFailureContents::new(Some(index), path, err);
+                Err(Error::SourceReadFailure(contents.into()).into())
             }
         }
     }
 
-    pub fn append_from_file(&self, index: StoreIndex, path: &std::path::Path) -> Result<StoreEntryAsync<'a>> {
-        if self.has(&index) {
-            Err(Error::AlreadyInStore(index.clone()).into())
-        } else {
-            let mut store_guard = self.indices.write().unwrap();
-
-            match fs::read_to_string(path) {
-                Ok(source) => {
-                    #[allow(clippy::arc_with_non_send_sync)]
-                    let entry: StoreEntryAsync<'a> = Arc::new(RwLock::new(StoreEntry {
-                        store: Arc::downgrade(&self.get_handle()),
-                        index: index.clone(),
-                        source,
-                    }));
-
-                    store_guard.insert(index.clone(), entry.clone());
-
-                    let mut env_guard = self.env.write().unwrap();
-                    let result =
-                        env_guard.add_template_owned(index, entry.read().unwrap().source.clone());
-                    match result {
-                        Ok(_) => Ok(entry),
-                        Err(err) => Err(Error::Native(err).into()),
-                    }
+    /// Compiles `source` and checks its dependencies, and only then adds the
+    /// entry, so a template that fails either leaves nothing behind.
+    fn register(&self, index: StoreIndex, source: String, path: Option<PathBuf>) -> Result<StoreEntryAsync<'a>> {
+        let mut indices_guard = self.indices.write().map_err(Error::from)?;
+        if indices_guard.contains_key(&index) {
+            return Err(Error::AlreadyInStore(index).into());
+        }
+        let dependencies = deps::scan(&source);
+        self.check_dependencies(&indices_guard, &index, &dependencies)?;
+
+        let mut env_guard = self.env.write().map_err(Error::from)?;
+        if let Err(err) = env_guard.add_template_owned(index.clone(), source.clone()) {
+            let contents = TemplateFailureContents::new(err, &index, Some(&source), path);
+            return Err(Error::CompileFailure(contents.into()).into());
+        }
+
+        let entry: StoreEntryAsync<'a> = Arc::new(RwLock::new(StoreEntry {
+            store: self.handle.clone(),
+            index: index.clone(),
+            source,
+            path,
+            dependencies,
+            version: 0,
+        }));
+        indices_guard.insert(index, entry.clone());
+        Ok(entry)
+    }
+
+    /// Reads `path` without blocking the executor, then registers it like
+    /// `append_raw`. Takes the store's handle rather than a guard so that no
+    /// lock is held across the read.
+    #[cfg(feature = "tokio")]
+    pub async fn append_from_file_async(
+        store: &Arc<RwLock<Self>>,
+        index: StoreIndex,
+        path: &std::path::Path,
+    ) -> Result<StoreEntryAsync<'a>> {
+        if store.read().map_err(Error::from)?.is_registered(&index)? {
+            return Err(Error::AlreadyInStore(index).into());
+        }
+
+        match tokio::fs::read_to_string(path).await {
+            Ok(source) => store.read().map_err(Error::from)?.register(index, source, Some(path.into())),
+            Err(err) => {
+                let contents = SourceReadFailureContents::new(Some(index), path, err);
+                Err(Error::SourceReadFailure(contents.into()).into())
+            }
+        }
+    }
+
+    /// Reads the entry's file again and swaps the new source into both the
+    /// entry and the environment. Nothing changes if the file cannot be read
+    /// or does not compile.
+    pub fn reload(&self, index: &StoreIndex) -> Result<StoreEntryAsync<'a>> {
+        let entry = self.get_registered(index)?;
+        let path = match &entry.read().map_err(Error::from)?.path {
+            Some(path) => path.clone(),
+            None => return Err(Error::NotFromFile(index.clone()).into()),
+        };
+
+        let source = match fs::read_to_string(&path) {
+            Ok(source) => source,
+            Err(err) => {
+                let contents = SourceReadFailureContents::new(Some(index.clone()), path, err);
+                return Err(Error::SourceReadFailure(contents.into()).into());
+            }
+        };
+
+        self.replace(index, source)
+    }
+
+    /// Swaps in a new source for a registered entry, updating the entry and
+    /// the environment together. Nothing changes if the source does not
+    /// compile or its dependencies do not check out.
+    pub fn replace(&self, index: &StoreIndex, source: String) -> Result<StoreEntryAsync<'a>> {
+        let indices_guard = self.indices.write().map_err(Error::from)?;
+        let entry = match indices_guard.get(index) {
+            Some(entry) => entry.clone(),
+            None => return Err(Error::MissingIndex(index.clone()).into()),
+        };
+        let dependencies = deps::scan(&source);
+        self.check_dependencies(&indices_guard, index, &dependencies)?;
+
+        // rendering locks the entry before the environment, so do the same
+        let mut entry_guard = entry.write().map_err(Error::from)?;
+        let mut env_guard = self.env.write().map_err(Error::from)?;
+        match env_guard.add_template_owned(index.clone(), source.clone()) {
+            Ok(_) => {
+                entry_guard.source = source;
+                entry_guard.dependencies = dependencies;
+                entry_guard.version += 1;
+                drop(entry_guard);
+                Ok(entry)
+            }
+            Err(err) => {
+                let contents = TemplateFailureContents::new(err, index, Some(&source), entry_guard.path.clone());
+                Err(Error::CompileFailure(contents.into()).into())
+            }
+        }
+    }
+
+    /// Takes `index` out of the store and the environment. An index that is
+    /// also on a search path can be loaded again afterwards. Fails while other
+    /// entries cannot render without it, unless it is on a search path.
+    pub fn remove(&self, index: &StoreIndex) -> Result<()> {
+        let mut indices_guard = self.indices.write().map_err(Error::from)?;
+        if indices_guard.contains_key(index) && self.resolve(index)?.is_none() {
+            let mut dependents: Vec<StoreIndex> = Vec::new();
+            for (other, entry) in indices_guard.iter() {
+                let entry_guard = entry.read().map_err(Error::from)?;
+                if other != index && entry_guard.dependencies.iter().any(|x| &x.index == index && !x.optional) {
+                    dependents.push(other.clone());
                 }
-                Err(_) => Err(Error::SourceReadFailure(path.into()).into()),
+            }
+            if !dependents.is_empty() {
+                dependents.sort();
+                return Err(Error::HasDependents {
+                    index: index.clone(),
+                    dependents,
+                }
+                .into());
+            }
+        }
+
+        match indices_guard.remove(index) {
+            Some(entry) => {
+                entry.write().map_err(Error::from)?.version += 1;
+                self.env.write().map_err(Error::from)?.remove_template(index);
+                Ok(())
+            }
+            None => Err(Error::MissingIndex(index.clone()).into()),
+        }
+    }
+
+    /// Fails on the first dependency that is neither registered nor on a
+    /// search path, unless it is optional, and on the first that leads back
+    /// to `index`. Takes the held indices guard, and never locks `index`'s
+    /// own entry, which `replace` may hold.
+    fn check_dependencies(
+        &self,
+        indices_guard: &HashMap<StoreIndex, StoreEntryAsync<'a>>,
+        index: &StoreIndex,
+        dependencies: &[Dependency],
+    ) -> Result<()> {
+        for dependency in dependencies.iter() {
+            // a template naming itself is reported as a cycle below
+            if !dependency.optional
+                && &dependency.index != index
+                && !indices_guard.contains_key(&dependency.index)
+                && self.resolve(&dependency.index)?.is_none()
+            {
+                return Err(Error::MissingDependency {
+                    index: index.clone(),
+                    dependency: dependency.index.clone(),
+                }
+                .into());
+            }
+        }
+
+        // unregistered templates on a search path are followed too, since
+        // they are loaded as soon as anything renders them
+        let lookup = |x: &StoreIndex| -> Vec<StoreIndex> {
+            if x == index {
+                return Vec::new();
+            }
+            match indices_guard.get(x) {
+                Some(entry) => deps::indices_of(entry),
+                None => self.scan_search_paths(x),
+            }
+        };
+        for dependency in dependencies.iter() {
+            if let Some(path) = deps::find_path(&dependency.index, index, &lookup) {
+                let cycle = [vec![index.clone()], path].concat();
+                return Err(Error::DependencyCycle(cycle).into());
             }
         }
+        Ok(())
     }
 
-    pub fn has(&self, index: &StoreIndex) -> bool {
-        let indices_guard = self.indices.read().unwrap();
-        indices_guard.contains_key(index)
+    /// What registered entry `index` extends, includes or imports.
+    pub fn dependencies(&self, index: &StoreIndex) -> Result<Vec<Dependency>> {
+        let entry = self.get_registered(index)?;
+        let entry_guard = entry.read().map_err(Error::from)?;
+        Ok(entry_guard.dependencies.clone())
+    }
+
+    /// The registered entries that extend, include or import `index`
+    /// directly, sorted.
+    pub fn dependents(&self, index: &StoreIndex) -> Result<Vec<StoreIndex>> {
+        let indices_guard = self.indices.read().map_err(Error::from)?;
+        let mut result: Vec<StoreIndex> = Vec::new();
+        for (other, entry) in indices_guard.iter() {
+            let entry_guard = entry.read().map_err(Error::from)?;
+            if entry_guard.dependencies.iter().any(|x| &x.index == index) {
+                result.push(other.clone());
+            }
+        }
+        result.sort();
+        Ok(result)
     }
 
-    pub fn get(&self, index: &StoreIndex) -> StoreEntryAsync<'a> {
-        self.indices
-            .read()
-            .unwrap()
-            .get(index)
-            .unwrap_or_else(|| panic!("Tried to get non-existent index '{}'", index))
-            .clone()
+    /// The version of every registered entry that `index` extends, includes
+    /// or imports, directly or through other entries. Dependencies that are
+    /// not registered are left out.
+    pub fn dependency_versions(&self, index: &StoreIndex) -> Result<BTreeMap<StoreIndex, u64>> {
+        let indices_guard = self.indices.read().map_err(Error::from)?;
+        if !indices_guard.contains_key(index) {
+            return Err(Error::MissingIndex(index.clone()).into());
+        }
+
+        let mut result: BTreeMap<StoreIndex, u64> = BTreeMap::new();
+        let mut pending: Vec<StoreIndex> = vec![index.clone()];
+
+        while let Some(current) = pending.pop() {
+            let entry_guard = match indices_guard.get(&current) {
+                Some(entry) => entry.read().map_err(Error::from)?,
+                None => continue,
+            };
+            if &current != index {
+                result.insert(current, entry_guard.version);
+            }
+            for dependency in entry_guard.dependencies.iter() {
+                if &dependency.index != index && !result.contains_key(&dependency.index) {
+                    pending.push(dependency.index.clone());
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// The file registered entry `index` was read from. Used while the
+    /// entry itself may be locked, so it gives up instead of waiting.
+    fn path_of(&self, index: &StoreIndex) -> Option<PathBuf> {
+        let indices_guard = self.indices.try_read().ok()?;
+        let entry_guard = indices_guard.get(index)?.try_read().ok()?;
+        entry_guard.path.clone()
+    }
+
+    /// Registering an index that could also be loaded from a search path is
+    /// allowed; the registered entry shadows the file.
+    fn is_registered(&self, index: &StoreIndex) -> Result<bool> {
+        Ok(self.indices.read().map_err(Error::from)?.contains_key(index))
+    }
+
+    fn get_registered(&self, index: &StoreIndex) -> Result<StoreEntryAsync<'a>> {
+        let indices_guard = self.indices.read().map_err(Error::from)?;
+        match indices_guard.get(index) {
+            Some(entry) => Ok(entry.clone()),
+            None => Err(Error::MissingIndex(index.clone()).into()),
+        }
+    }
+
+    /// Whether `index` is registered or can be loaded from a search path.
+    pub fn has(&self, index: &StoreIndex) -> Result<bool> {
+        Ok(self.is_registered(index)? || self.resolve(index)?.is_some())
+    }
+
+    /// Entries found on a search path are loaded on first use.
+    pub fn get(&self, index: &StoreIndex) -> Result<StoreEntryAsync<'a>> {
+        match self.get_registered(index) {
+            Err(crate::Error::Template(Error::MissingIndex(_))) => self.load_lazily(index),
+            result => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn templates_others_need_are_not_removed() {
+        let store = TemplateStore::new();
+        let store_guard = store.read().unwrap();
+        store_guard.append_raw("part".into(), "<Row/>".into()).unwrap();
+        store_guard.append_raw("maybe".into(), "<Row/>".into()).unwrap();
+        store_guard
+            .append_raw("page".into(), "{% include 'part' %}{% include 'maybe' ignore missing %}".into())
+            .unwrap();
+
+        match store_guard.remove(&"part".into()) {
+            Err(crate::Error::Template(Error::HasDependents { index, dependents })) => {
+                assert_eq!(index, "part");
+                assert_eq!(dependents, ["page"]);
+            }
+            other => panic!("expected the removal to be refused, got {other:?}"),
+        }
+        assert!(store_guard.is_registered(&"part".into()).unwrap());
+
+        store_guard.remove(&"maybe".into()).unwrap();
+        store_guard.remove(&"page".into()).unwrap();
+        store_guard.remove(&"part".into()).unwrap();
+        assert!(!store_guard.has(&"part".into()).unwrap());
+    }
+
+    #[test]
+    fn entries_report_a_dropped_store() {
+        let store = TemplateStore::new();
+        let entry = store.read().unwrap().append_raw("page".into(), "<Page/>".into()).unwrap();
+        let weak_store = Arc::downgrade(&store);
+        assert!(store.read().unwrap().get_handle().unwrap().read().unwrap().has(&"page".into()).unwrap());
+
+        drop(store);
+        assert!(weak_store.upgrade().is_none());
+        let entry_guard = entry.read().unwrap();
+        assert!(matches!(entry_guard.store(), Err(crate::Error::Template(Error::StoreDropped(_)))));
+        assert!(matches!(
+            entry_guard.render(minijinja::context! {}),
+            Err(crate::Error::Template(Error::StoreDropped(_)))
+        ));
+
+        let dom_store = crate::xml::XmlStore::new();
+        let dom_entry = dom_store.write().unwrap().append_from_source("page".into(), "<Page/>".into()).unwrap();
+        drop(dom_store);
+        assert!(dom_entry.read().unwrap().store.upgrade().is_none());
     }
 }
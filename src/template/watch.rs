@@ -0,0 +1,146 @@
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock, Weak};
+use std::vec::Vec;
+
+use notify::{EventKind, RecursiveMode, Watcher as _};
+
+use super::{Error, StoreIndex, TemplateStore};
+use crate::xml::XmlStore;
+use crate::Result;
+
+/// What one change on disk led to. `templates` lists the template indices
+/// reloaded, `entries` the `XmlStore` indices rendered again because of them.
+/// A failure under an empty index means the store itself could not be read.
+#[derive(Debug, Default)]
+pub struct ReloadEvent {
+    pub templates: Vec<StoreIndex>,
+    pub entries: Vec<StoreIndex>,
+    pub failed: Vec<(StoreIndex, crate::Error)>,
+}
+
+/// Keeps watching for as long as it is alive.
+pub struct Watcher {
+    _watcher: notify::RecommendedWatcher,
+}
+
+impl std::fmt::Debug for Watcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Watcher")
+    }
+}
+
+impl TemplateStore<'static> {
+    /// Watches the file of every entry read with `append_from_file` and
+    /// reloads it when it changes, then renders dependent entries of
+    /// `xml_store` again. Entries added after this call are not watched.
+    pub fn watch(
+        &self,
+        xml_store: Option<Arc<RwLock<XmlStore>>>,
+        callback: impl Fn(&ReloadEvent) + Send + Sync + 'static,
+    ) -> Result<Watcher> {
+        let store: Weak<RwLock<TemplateStore<'static>>> = self.handle.clone();
+
+        // editors often replace files instead of writing them, so watch the
+        // directories they live in
+        let directories: BTreeSet<PathBuf> = self
+            .sources()?
+            .into_iter()
+            .filter_map(|(_, path)| path.canonicalize().ok())
+            .filter_map(|x| x.parent().map(Path::to_path_buf))
+            .collect();
+
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+            let event = match event {
+                Ok(event) if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) => event,
+                _ => return,
+            };
+            let store_handle = match store.upgrade() {
+                Some(store_handle) => store_handle,
+                None => return,
+            };
+
+            let reload_event = reload_paths(&store_handle, xml_store.as_ref(), &event.paths);
+            if !reload_event.templates.is_empty() || !reload_event.failed.is_empty() {
+                callback(&reload_event);
+            }
+        })
+        .map_err(Error::Watch)?;
+
+        for directory in directories.iter() {
+            watcher
+                .watch(directory, RecursiveMode::NonRecursive)
+                .map_err(Error::Watch)?;
+        }
+
+        Ok(Watcher { _watcher: watcher })
+    }
+
+    fn sources(&self) -> Result<Vec<(StoreIndex, PathBuf)>> {
+        let mut result: Vec<(StoreIndex, PathBuf)> = Vec::new();
+        for (index, entry) in self.indices.read().map_err(Error::from)?.iter() {
+            if let Some(path) = &entry.read().map_err(Error::from)?.path {
+                result.push((index.clone(), path.clone()));
+            }
+        }
+        Ok(result)
+    }
+}
+
+fn reload_paths(
+    store_handle: &Arc<RwLock<TemplateStore<'static>>>,
+    xml_store: Option<&Arc<RwLock<XmlStore>>>,
+    paths: &[PathBuf],
+) -> ReloadEvent {
+    let changed: Vec<PathBuf> = paths.iter().filter_map(|x| x.canonicalize().ok()).collect();
+
+    let mut result = ReloadEvent::default();
+    let sources = match store_handle.read().map_err(Error::from) {
+        Ok(store_guard) => store_guard.sources(),
+        Err(err) => Err(err.into()),
+    };
+    let mut indices: Vec<StoreIndex> = match sources {
+        Ok(sources) => sources
+            .into_iter()
+            .filter(|(_, path)| path.canonicalize().is_ok_and(|x| changed.contains(&x)))
+            .map(|(index, _)| index)
+            .collect(),
+        Err(err) => {
+            result.failed.push((StoreIndex::new(), err));
+            return result;
+        }
+    };
+    indices.sort();
+
+    for index in indices.into_iter() {
+        // the template store guard has to be gone before rendering again
+        let reloaded = match store_handle.read().map_err(Error::from) {
+            Ok(store_guard) => store_guard.reload(&index),
+            Err(err) => Err(err.into()),
+        };
+        match reloaded {
+            Ok(_) => result.templates.push(index.clone()),
+            Err(err) => {
+                result.failed.push((index, err));
+                continue;
+            }
+        }
+
+        let xml_store = match xml_store {
+            Some(xml_store) => xml_store,
+            None => continue,
+        };
+        let dependents = match xml_store.read().map_err(crate::xml::Error::from) {
+            Ok(xml_guard) => xml_guard.rerender_dependents(&index),
+            Err(err) => vec![(index.clone(), Err(err.into()))],
+        };
+        for (entry_index, rerendered) in dependents.into_iter() {
+            match rerendered {
+                Ok(_) => result.entries.push(entry_index),
+                Err(err) => result.failed.push((entry_index, err)),
+            }
+        }
+    }
+
+    result
+}
@@ -0,0 +1,211 @@
+use std::vec::Vec;
+
+use super::observe::{self, NodeEvent};
+use super::{Namespace, NodeAsync, XmlNode};
+
+pub(super) fn class_key() -> (Namespace, String) {
+    ("Default".into(), "class".into())
+}
+
+impl XmlNode {
+    /// The whitespace-separated names in the `class` attribute, in order and
+    /// without repeats.
+    pub fn classes(&self) -> Vec<String> {
+        let mut result: Vec<String> = Vec::new();
+        for class in self.attributes.get(&class_key()).map(String::as_str).unwrap_or_default().split_whitespace() {
+            if !result.iter().any(|x| x == class) {
+                result.push(class.into());
+            }
+        }
+        result
+    }
+
+    pub fn has_class(&self, class: &str) -> bool {
+        let value = self.attributes.get(&class_key()).map(String::as_str).unwrap_or_default();
+        value.split_whitespace().any(|x| x == class)
+    }
+
+    /// Returns whether `class` was added. A name that is empty or contains
+    /// whitespace is never added. Changes made here do not notify observers;
+    /// `NodeAsync::add_class` does.
+    pub fn add_class(&mut self, class: &str) -> bool {
+        if class.is_empty() || class.contains(char::is_whitespace) || self.has_class(class) {
+            return false;
+        }
+        let mut classes = self.classes();
+        classes.push(class.into());
+        self.set_classes(classes);
+        true
+    }
+
+    /// Returns whether `class` was there to remove. The attribute goes away
+    /// with the last class.
+    pub fn remove_class(&mut self, class: &str) -> bool {
+        if !self.has_class(class) {
+            return false;
+        }
+        let classes = self.classes().into_iter().filter(|x| x != class).collect();
+        self.set_classes(classes);
+        true
+    }
+
+    /// Adds `class` if it is missing and removes it otherwise. Returns whether
+    /// the node has it afterwards.
+    pub fn toggle_class(&mut self, class: &str) -> bool {
+        if self.has_class(class) {
+            !self.remove_class(class)
+        } else {
+            self.add_class(class)
+        }
+    }
+
+    fn set_classes(&mut self, classes: Vec<String>) {
+        if classes.is_empty() {
+            self.attributes.remove(&class_key());
+        } else {
+            self.attributes.insert(class_key(), classes.join(" "));
+        }
+    }
+}
+
+impl NodeAsync {
+    /// `XmlNode::add_class`, notifying observers.
+    pub fn add_class(&self, class: &str) -> bool {
+        self.change_classes(|x| x.add_class(class))
+    }
+
+    /// `XmlNode::remove_class`, notifying observers.
+    pub fn remove_class(&self, class: &str) -> bool {
+        self.change_classes(|x| x.remove_class(class))
+    }
+
+    /// `XmlNode::toggle_class`, notifying observers.
+    pub fn toggle_class(&self, class: &str) -> bool {
+        self.change_classes(|x| x.toggle_class(class))
+    }
+
+    fn change_classes(&self, change: impl FnOnce(&mut XmlNode) -> bool) -> bool {
+        let (result, value) = {
+            let mut node_guard = self.write();
+            let before = node_guard.attributes.get(&class_key()).cloned();
+            let result = change(&mut node_guard);
+            let after = node_guard.attributes.get(&class_key()).cloned();
+            if before == after {
+                return result;
+            }
+            (result, after)
+        };
+
+        observe::dispatch(vec![NodeEvent::AttributeChanged {
+            node: self.clone(),
+            key: class_key(),
+            value,
+        }]);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::xml::{parse_nodes, IdStrategy, NodeAsync, XmlStore};
+
+    fn node(source: &str) -> NodeAsync {
+        parse_nodes(&"test".into(), source, &IdStrategy::default()).unwrap().0.remove(0)
+    }
+
+    fn class_attribute(node: &NodeAsync) -> Option<String> {
+        node.read().get_attribute("Default", "class")
+    }
+
+    #[test]
+    fn classes_skip_repeats_and_extra_whitespace() {
+        let row = node("<Row class=\"  a b\ta  c \"/>");
+        assert_eq!(row.read().classes(), ["a", "b", "c"]);
+        assert!(row.read().has_class("c"));
+        assert!(!row.read().has_class(""));
+        assert!(!row.read().has_class("a b"));
+        assert!(node("<Row/>").read().classes().is_empty());
+    }
+
+    #[test]
+    fn add_class_refuses_empty_whitespace_and_present_names() {
+        let row = node(r#"<Row class="a"/>"#);
+        assert!(!row.add_class(""));
+        assert!(!row.add_class("b c"));
+        assert!(!row.add_class("a"));
+        assert_eq!(class_attribute(&row).as_deref(), Some("a"));
+
+        assert!(row.add_class("b"));
+        assert_eq!(class_attribute(&row).as_deref(), Some("a b"));
+
+        let bare = node("<Row/>");
+        assert!(bare.add_class("a"));
+        assert_eq!(class_attribute(&bare).as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn remove_class_drops_repeats_and_the_emptied_attribute() {
+        let row = node(r#"<Row class="a b a"/>"#);
+        assert!(!row.remove_class("c"));
+        assert_eq!(class_attribute(&row).as_deref(), Some("a b a"));
+
+        assert!(row.remove_class("a"));
+        assert_eq!(class_attribute(&row).as_deref(), Some("b"));
+        assert!(row.remove_class("b"));
+        assert_eq!(class_attribute(&row), None);
+        assert!(!row.remove_class("b"));
+    }
+
+    #[test]
+    fn toggle_class_reports_whether_the_class_is_there_after() {
+        let row = node("<Row/>");
+        assert!(row.toggle_class("a"));
+        assert!(!row.toggle_class("a"));
+        assert_eq!(class_attribute(&row), None);
+        // a name that can never be added stays absent
+        assert!(!row.toggle_class("b c"));
+        assert_eq!(class_attribute(&row), None);
+    }
+
+    #[test]
+    fn class_index_follows_class_changes_and_tree_mutations() {
+        let store = XmlStore::new();
+        let source = r#"<Column><Row id="first" class="a"/><Row id="second"/></Column>"#;
+        let entry = store.write().unwrap().append_from_source("test".into(), source.into()).unwrap();
+        let entry_guard = entry.read().unwrap();
+        let ids = |class: &str| -> Vec<String> {
+            let mut result: Vec<String> = entry_guard
+                .get_elements_by_class_name(class)
+                .iter()
+                .filter_map(|x| x.read().get_attribute("Default", "id"))
+                .collect();
+            result.sort();
+            result
+        };
+        let (first, second) = (
+            entry_guard.get_element_by_id("first").unwrap(),
+            entry_guard.get_element_by_id("second").unwrap(),
+        );
+
+        second.add_class("a");
+        assert_eq!(ids("a"), ["first", "second"]);
+        first.toggle_class("a");
+        first.add_class("b");
+        assert_eq!(ids("a"), ["second"]);
+        assert_eq!(ids("b"), ["first"]);
+
+        // a detached subtree leaves the index, and comes back with its classes
+        first.detach();
+        assert!(ids("b").is_empty());
+        second.append_child(&first).unwrap();
+        assert_eq!(ids("b"), ["first"]);
+
+        // classes changed while detached are seen once it is back in the tree
+        second.detach();
+        first.add_class("c");
+        assert!(ids("a").is_empty() && ids("c").is_empty());
+        entry_guard.nodes[0].append_child(&second).unwrap();
+        assert_eq!(ids("a"), ["second"]);
+        assert_eq!(ids("c"), ["first"]);
+    }
+}
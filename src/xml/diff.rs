@@ -0,0 +1,419 @@
+use std::collections::{HashMap, VecDeque};
+use std::mem::discriminant;
+use std::vec::Vec;
+
+use super::serialize::is_generated_id;
+use super::{Namespace, NodeAsync, NodeEvent, NodeKind, XmlNode};
+
+#[derive(Debug, Clone)]
+pub enum NodeChange {
+    /// `value` is `None` when the attribute was removed.
+    Attribute {
+        key: (Namespace, String),
+        value: Option<String>,
+    },
+    /// New text, comment or processing instruction contents.
+    Content(NodeKind),
+}
+
+/// One step of turning the old tree into the new one. `parent` is `None` for
+/// the entry's root nodes. An insertion's `index` and a move's `to` are
+/// positions in the updated child list; a removal's `index` and a move's
+/// `from` are positions in the list before the update.
+#[derive(Debug, Clone)]
+pub enum Patch {
+    Insert {
+        parent: Option<NodeAsync>,
+        index: usize,
+        node: NodeAsync,
+    },
+    Remove {
+        parent: Option<NodeAsync>,
+        node: NodeAsync,
+        index: usize,
+    },
+    Update {
+        node: NodeAsync,
+        change: NodeChange,
+    },
+    Move {
+        parent: Option<NodeAsync>,
+        node: NodeAsync,
+        from: usize,
+        to: usize,
+    },
+}
+
+/// The user-supplied id of `node`, if it has one. Generated ids may differ
+/// on every parse, or follow a node's position rather than the node, so
+/// they cannot pair nodes across renders.
+fn key_of(node: &XmlNode) -> Option<String> {
+    node.get_attribute("Default", "id").filter(|x| !is_generated_id(x))
+}
+
+fn same_shape(old: &XmlNode, new: &XmlNode) -> bool {
+    discriminant(&old.kind) == discriminant(&new.kind) && old.name == new.name && old.prefix == new.prefix
+}
+
+/// Reconciles `old` against `new`, keeping every old node that has a
+/// counterpart in `new` and patching it in place. Returns the child list to
+/// use from now on; parent links of reused and inserted nodes are left to the
+/// caller.
+pub(super) fn reconcile(
+    parent: Option<&NodeAsync>,
+    old: &[NodeAsync],
+    new: Vec<NodeAsync>,
+    patches: &mut Vec<Patch>,
+) -> Vec<NodeAsync> {
+    let mut keyed: HashMap<String, usize> = HashMap::new();
+    let mut unkeyed: VecDeque<usize> = VecDeque::new();
+    for (position, node) in old.iter().enumerate() {
+        match key_of(&node.read()) {
+            Some(key) => {
+                keyed.insert(key, position);
+            }
+            None => unkeyed.push_back(position),
+        }
+    }
+
+    // pair each new node with an old one: by key when it has one, otherwise
+    // with the first unused unkeyed old node of the same shape
+    let mut used: Vec<bool> = vec![false; old.len()];
+    let mut pairs: Vec<Option<usize>> = Vec::with_capacity(new.len());
+    for node in new.iter() {
+        let node_guard = node.read();
+        let candidate = match key_of(&node_guard) {
+            Some(key) => keyed.get(&key).copied(),
+            None => unkeyed
+                .iter()
+                .position(|x| !used[*x] && same_shape(&old[*x].read(), &node_guard))
+                .map(|x| unkeyed.remove(x).unwrap()),
+        };
+        let candidate = candidate.filter(|x| !used[*x] && same_shape(&old[*x].read(), &node_guard));
+        if let Some(position) = candidate {
+            used[position] = true;
+        }
+        pairs.push(candidate);
+    }
+
+    for (position, node) in old.iter().enumerate() {
+        if !used[position] {
+            node.write().parent = None;
+            patches.push(Patch::Remove {
+                parent: parent.cloned(),
+                node: node.clone(),
+                index: position,
+            });
+        }
+    }
+
+    // matched nodes outside the longest run that kept its relative order are
+    // the ones that have to move
+    let stable = longest_increasing(&pairs);
+
+    let mut result: Vec<NodeAsync> = Vec::with_capacity(new.len());
+    for (index, (node, pair)) in new.into_iter().zip(pairs.iter()).enumerate() {
+        match pair {
+            Some(from) => {
+                let kept = old[*from].clone();
+                if !stable[index] {
+                    patches.push(Patch::Move {
+                        parent: parent.cloned(),
+                        node: kept.clone(),
+                        from: *from,
+                        to: index,
+                    });
+                }
+                update(&kept, node, patches);
+                result.push(kept);
+            }
+            None => {
+                patches.push(Patch::Insert {
+                    parent: parent.cloned(),
+                    index,
+                    node: node.clone(),
+                });
+                result.push(node);
+            }
+        }
+    }
+
+    result
+}
+
+/// Copies everything from `new` onto `old`, recording what changed.
+fn update(old: &NodeAsync, new: NodeAsync, patches: &mut Vec<Patch>) {
+    let mut new_guard = new.write();
+    let new_children = std::mem::take(&mut new_guard.children);
+
+    {
+        let mut old_guard = old.write();
+
+        if old_guard.kind != new_guard.kind {
+            old_guard.kind = new_guard.kind.clone();
+            patches.push(Patch::Update {
+                node: old.clone(),
+                change: NodeChange::Content(old_guard.kind.clone()),
+            });
+        }
+
+        let id_key: (Namespace, String) = ("Default".into(), "id".into());
+        let mut keys: Vec<(Namespace, String)> = old_guard
+            .attributes
+            .keys()
+            .chain(new_guard.attributes.keys())
+            .filter(|x| **x != id_key)
+            .cloned()
+            .collect();
+        keys.sort();
+        keys.dedup();
+        for key in keys {
+            let value = new_guard.attributes.get(&key).cloned();
+            if old_guard.attributes.get(&key) == value.as_ref() {
+                continue;
+            }
+            match &value {
+                Some(value) => old_guard.attributes.insert(key.clone(), value.clone()),
+                None => old_guard.attributes.remove(&key),
+            };
+            patches.push(Patch::Update {
+                node: old.clone(),
+                change: NodeChange::Attribute { key, value },
+            });
+        }
+
+        old_guard.namespace = new_guard.namespace.clone();
+        old_guard.namespaces = new_guard.namespaces.clone();
+        old_guard.included_from = new_guard.included_from.clone();
+    }
+    drop(new_guard);
+
+    let old_children: Vec<NodeAsync> = old.read().children.clone();
+    let children = reconcile(Some(old), &old_children, new_children, patches);
+    for child in children.iter() {
+        child.write().parent = Some(old.downgrade());
+    }
+    old.write().children = children;
+}
+
+/// Marks the entries of the longest run of `pairs` whose old positions are
+/// strictly increasing. Unpaired entries are never marked.
+fn longest_increasing(pairs: &[Option<usize>]) -> Vec<bool> {
+    // patience sorting over the paired entries, keeping back-links
+    let mut tails: Vec<usize> = Vec::new();
+    let mut previous: Vec<Option<usize>> = vec![None; pairs.len()];
+
+    for (index, pair) in pairs.iter().enumerate() {
+        let value = match pair {
+            Some(value) => *value,
+            None => continue,
+        };
+        let slot = tails.partition_point(|x| pairs[*x].unwrap() < value);
+        if slot > 0 {
+            previous[index] = Some(tails[slot - 1]);
+        }
+        if slot == tails.len() {
+            tails.push(index);
+        } else {
+            tails[slot] = index;
+        }
+    }
+
+    let mut result = vec![false; pairs.len()];
+    let mut cursor = tails.last().copied();
+    while let Some(index) = cursor {
+        result[index] = true;
+        cursor = previous[index];
+    }
+    result
+}
+
+/// The observer events a list of patches amounts to. A move is reported as a
+/// removal followed by an insertion. Changes to an entry's root list have no
+/// parent node to report to and are left out.
+pub(super) fn events(patches: &[Patch]) -> Vec<NodeEvent> {
+    let mut result: Vec<NodeEvent> = Vec::new();
+
+    for patch in patches.iter() {
+        match patch {
+            Patch::Insert {
+                parent: Some(parent),
+                index,
+                node,
+            } => result.push(NodeEvent::ChildInserted {
+                parent: parent.clone(),
+                child: node.clone(),
+                index: *index,
+            }),
+            Patch::Remove {
+                parent: Some(parent),
+                node,
+                index,
+            } => result.push(NodeEvent::ChildRemoved {
+                parent: parent.clone(),
+                child: node.clone(),
+                index: *index,
+            }),
+            Patch::Move {
+                parent: Some(parent),
+                node,
+                from,
+                to,
+            } => {
+                result.push(NodeEvent::ChildRemoved {
+                    parent: parent.clone(),
+                    child: node.clone(),
+                    index: *from,
+                });
+                result.push(NodeEvent::ChildInserted {
+                    parent: parent.clone(),
+                    child: node.clone(),
+                    index: *to,
+                });
+            }
+            Patch::Update {
+                node,
+                change: NodeChange::Attribute { key, value },
+            } => result.push(NodeEvent::AttributeChanged {
+                node: node.clone(),
+                key: key.clone(),
+                value: value.clone(),
+            }),
+            Patch::Update {
+                node,
+                change: NodeChange::Content(kind),
+            } => result.push(NodeEvent::TextChanged {
+                node: node.clone(),
+                kind: kind.clone(),
+            }),
+            _ => {}
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xml::{parse_nodes, IdStrategy};
+
+    fn nodes(source: &str) -> Vec<NodeAsync> {
+        parse_nodes(&"test".into(), source, &IdStrategy::default()).unwrap().0
+    }
+
+    fn label(node: &NodeAsync) -> String {
+        let node_guard = node.read();
+        match key_of(&node_guard) {
+            Some(key) => format!("{}#{key}", node_guard.name),
+            None => node_guard.name.clone(),
+        }
+    }
+
+    fn describe(patch: &Patch) -> String {
+        let parent = |x: &Option<NodeAsync>| x.as_ref().map(label).unwrap_or_else(|| "root".into());
+        match patch {
+            Patch::Insert { parent: p, index, node } => format!("insert {} into {} at {index}", label(node), parent(p)),
+            Patch::Remove { parent: p, node, index } => format!("remove {} from {} at {index}", label(node), parent(p)),
+            Patch::Move { parent: p, node, from, to } => {
+                format!("move {} in {} from {from} to {to}", label(node), parent(p))
+            }
+            Patch::Update { node, change: NodeChange::Attribute { key, value } } => {
+                format!("set {}[{}] to {value:?}", label(node), key.1)
+            }
+            Patch::Update { node, change: NodeChange::Content(kind) } => format!("change {} to {kind:?}", label(node)),
+        }
+    }
+
+    /// Reconciles the roots of `old` against those of `new`, returning the
+    /// old roots, the reconciled ones and the patches described.
+    fn diff(old: &str, new: &str) -> (Vec<NodeAsync>, Vec<NodeAsync>, Vec<String>) {
+        let old = nodes(old);
+        let mut patches: Vec<Patch> = Vec::new();
+        let result = reconcile(None, &old, nodes(new), &mut patches);
+        (old, result, patches.iter().map(describe).collect())
+    }
+
+    fn kept(old: &[NodeAsync], result: &[NodeAsync]) -> Vec<Option<usize>> {
+        result.iter().map(|x| old.iter().position(|y| y.ptr_eq(x))).collect()
+    }
+
+    #[test]
+    fn reorder_moves_only_what_left_the_stable_run() {
+        let (old, result, patches) =
+            diff(r#"<A id="a"/><B id="b"/><C id="c"/>"#, r#"<C id="c"/><A id="a"/><B id="b"/>"#);
+        assert_eq!(patches, ["move C#c in root from 2 to 0"]);
+        assert_eq!(kept(&old, &result), [Some(2), Some(0), Some(1)]);
+    }
+
+    #[test]
+    fn insert_keeps_unkeyed_siblings_of_the_same_shape() {
+        let (old, result, patches) = diff("<Row/><Row/>", "<Row/><Column/><Row/>");
+        assert_eq!(patches, ["insert Column into root at 1"]);
+        assert_eq!(kept(&old, &result), [Some(0), None, Some(1)]);
+    }
+
+    #[test]
+    fn remove_reports_the_old_position() {
+        let (old, result, patches) =
+            diff(r#"<A id="a"/><B id="b"/><C id="c"/>"#, r#"<A id="a"/><C id="c"/>"#);
+        assert_eq!(patches, ["remove B#b from root at 1"]);
+        assert_eq!(kept(&old, &result), [Some(0), Some(2)]);
+    }
+
+    #[test]
+    fn keyed_node_that_changed_shape_is_replaced() {
+        let (old, result, patches) = diff(r#"<Row id="x"><Column/></Row>"#, r#"<Column id="x"><Column/></Column>"#);
+        assert_eq!(patches, ["remove Row#x from root at 0", "insert Column#x into root at 0"]);
+        assert_eq!(kept(&old, &result), [None]);
+    }
+
+    #[test]
+    fn mixed_keyed_and_unkeyed_siblings() {
+        let (old, result, patches) = diff(
+            r#"<Row/><Item id="a"/><Row/><Item id="b"/><Spacer/>"#,
+            r#"<Item id="b"/><Row/><Item id="a"/><Row/><Row/>"#,
+        );
+        assert_eq!(
+            patches,
+            [
+                "remove Spacer from root at 4",
+                "move Item#b in root from 3 to 0",
+                "insert Row into root at 4",
+            ]
+        );
+        assert_eq!(kept(&old, &result), [Some(3), Some(0), Some(1), Some(2), None]);
+    }
+
+    #[test]
+    fn nested_changes_update_kept_nodes_in_place() {
+        let (old, result, patches) = diff(
+            r#"<Row id="r" class="a"><Column/>one<Column/></Row>"#,
+            r#"<Row id="r" class="b" hidden="true"><Column/>two</Row>"#,
+        );
+        assert_eq!(
+            patches,
+            [
+                "set Row#r[class] to Some(\"b\")",
+                "set Row#r[hidden] to Some(\"true\")",
+                "remove Column from Row#r at 2",
+                "change text-content to Text(\"two\")",
+            ]
+        );
+        assert!(result[0].ptr_eq(&old[0]));
+
+        let children = result[0].read().children.clone();
+        assert_eq!(children.len(), 2);
+        for child in children.iter() {
+            assert!(child.parent().unwrap().ptr_eq(&result[0]));
+        }
+    }
+
+    #[test]
+    fn longest_increasing_skips_unpaired_entries() {
+        let pairs = [Some(3), None, Some(0), Some(1), Some(2)];
+        assert_eq!(longest_increasing(&pairs), [false, false, true, true, true]);
+        assert_eq!(longest_increasing(&[Some(1), Some(0)]), [false, true]);
+        assert!(longest_increasing(&[None, None]).iter().all(|x| !x));
+    }
+}
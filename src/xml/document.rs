@@ -0,0 +1,438 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, RwLock};
+use std::vec::Vec;
+
+use super::observe::Observers;
+use super::select::{NodeSelector, NodeView, SelectorTree};
+use super::{Namespace, NodeAsync, NodeKind, StoreEntry, StoreIndex, XmlNode};
+
+/// Position of a node in its `XmlDocument`'s arena.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeIndex(usize);
+
+#[derive(Debug, Clone)]
+pub struct DocumentNode {
+    pub kind: NodeKind,
+
+    pub prefix: Option<String>,
+    pub namespace: Option<Namespace>,
+    pub namespaces: Option<BTreeMap<String, String>>,
+
+    pub name: String,
+    pub attributes: HashMap<(Namespace, String), String>,
+    pub included_from: Option<StoreIndex>,
+
+    parent: Option<NodeIndex>,
+    first_child: Option<NodeIndex>,
+    last_child: Option<NodeIndex>,
+    previous_sibling: Option<NodeIndex>,
+    next_sibling: Option<NodeIndex>,
+}
+
+/// A whole tree stored in one contiguous arena, linked by index instead of
+/// by `Arc`. Reading it takes no per-node locks; share it behind a single
+/// lock with `lock`.
+#[derive(Debug, Clone, Default)]
+pub struct XmlDocument {
+    nodes: Vec<DocumentNode>,
+    roots: Vec<NodeIndex>,
+}
+pub type XmlDocumentAsync = Arc<RwLock<XmlDocument>>;
+
+pub struct Children<'d> {
+    document: &'d XmlDocument,
+    next: Option<NodeIndex>,
+}
+
+/// Pre-order walk of a subtree, driven by sibling and parent links alone.
+pub struct Descendants<'d> {
+    document: &'d XmlDocument,
+    root: NodeIndex,
+    next: Option<NodeIndex>,
+}
+
+impl DocumentNode {
+    pub fn parent(&self) -> Option<NodeIndex> {
+        self.parent
+    }
+
+    pub fn first_child(&self) -> Option<NodeIndex> {
+        self.first_child
+    }
+
+    pub fn last_child(&self) -> Option<NodeIndex> {
+        self.last_child
+    }
+
+    pub fn previous_sibling(&self) -> Option<NodeIndex> {
+        self.previous_sibling
+    }
+
+    pub fn next_sibling(&self) -> Option<NodeIndex> {
+        self.next_sibling
+    }
+
+    pub fn is_element(&self) -> bool {
+        self.kind == NodeKind::Element
+    }
+
+    pub fn get_attribute(&self, namespace: &str, attribute: &str) -> Option<&str> {
+        let key = &(namespace.into(), attribute.into());
+        self.attributes.get(key).map(String::as_str)
+    }
+}
+
+impl XmlDocument {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_nodes(roots: &[NodeAsync]) -> Self {
+        let mut document = Self::new();
+        for root in roots.iter() {
+            document.copy_in(root, None);
+        }
+        document
+    }
+
+    pub fn lock(self) -> XmlDocumentAsync {
+        Arc::new(RwLock::new(self))
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn roots(&self) -> &[NodeIndex] {
+        &self.roots
+    }
+
+    pub fn get(&self, index: NodeIndex) -> &DocumentNode {
+        &self.nodes[index.0]
+    }
+
+    pub fn get_mut(&mut self, index: NodeIndex) -> &mut DocumentNode {
+        &mut self.nodes[index.0]
+    }
+
+    pub fn children(&self, index: NodeIndex) -> Children<'_> {
+        Children {
+            document: self,
+            next: self.get(index).first_child,
+        }
+    }
+
+    /// `index` and everything below it, in document order.
+    pub fn descendants(&self, index: NodeIndex) -> Descendants<'_> {
+        Descendants {
+            document: self,
+            root: index,
+            next: Some(index),
+        }
+    }
+
+    pub fn get_leaves(&self, index: NodeIndex) -> Vec<NodeIndex> {
+        self.descendants(index)
+            .filter(|x| self.get(*x).first_child.is_none())
+            .collect()
+    }
+
+    /// `root` and every node below it that matches, in document order.
+    pub fn select(&self, selector: &NodeSelector, root: NodeIndex) -> Vec<NodeIndex> {
+        self.descendants(root)
+            .filter(|x| selector.matches_in(self, x))
+            .collect()
+    }
+
+    /// Appends a node built from `node`'s data, without its children, under
+    /// `parent` or as a new root.
+    pub fn append(&mut self, parent: Option<NodeIndex>, node: &XmlNode) -> NodeIndex {
+        let index = NodeIndex(self.nodes.len());
+        let previous_sibling = match parent {
+            Some(parent) => self.get(parent).last_child,
+            None => self.roots.last().copied(),
+        };
+
+        self.nodes.push(DocumentNode {
+            kind: node.kind.clone(),
+
+            prefix: node.prefix.clone(),
+            namespace: node.namespace.clone(),
+            namespaces: node.namespaces.clone(),
+
+            name: node.name.clone(),
+            attributes: node.attributes.clone(),
+            included_from: node.included_from.clone(),
+
+            parent,
+            first_child: None,
+            last_child: None,
+            previous_sibling,
+            next_sibling: None,
+        });
+
+        if let Some(previous_sibling) = previous_sibling {
+            self.get_mut(previous_sibling).next_sibling = Some(index);
+        }
+        match parent {
+            Some(parent) => {
+                let parent_node = self.get_mut(parent);
+                parent_node.first_child.get_or_insert(index);
+                parent_node.last_child = Some(index);
+            }
+            None => self.roots.push(index),
+        }
+
+        index
+    }
+
+    /// Builds a `NodeAsync` tree for each root, with fresh parent links and no
+    /// observers.
+    pub fn to_nodes(&self) -> Vec<NodeAsync> {
+        self.roots.iter().map(|x| self.to_node(*x)).collect()
+    }
+
+    pub fn to_node(&self, index: NodeIndex) -> NodeAsync {
+        let source = self.get(index);
+        let node: NodeAsync = XmlNode {
+            kind: source.kind.clone(),
+
+            prefix: source.prefix.clone(),
+            namespace: source.namespace.clone(),
+            namespaces: source.namespaces.clone(),
+
+            name: source.name.clone(),
+            attributes: source.attributes.clone(),
+
+            children: Vec::default(),
+            parent: None,
+            included_from: source.included_from.clone(),
+
+            observers: Observers::default(),
+        }
+        .into();
+
+        let children: Vec<NodeAsync> = self.children(index).map(|x| self.to_node(x)).collect();
+        for child in children.iter() {
+            child.write().parent = Some(node.downgrade());
+        }
+        node.write().children = children;
+
+        node
+    }
+
+    fn copy_in(&mut self, node: &NodeAsync, parent: Option<NodeIndex>) {
+        let node_guard = node.read();
+        let index = self.append(parent, &node_guard);
+        for child in node_guard.children.iter() {
+            self.copy_in(child, Some(index));
+        }
+    }
+}
+
+impl StoreEntry {
+    pub fn to_document(&self) -> XmlDocument {
+        XmlDocument::from_nodes(&self.nodes)
+    }
+}
+
+impl SelectorTree for XmlDocument {
+    type Node = NodeIndex;
+
+    fn view<R>(&self, node: &NodeIndex, f: impl FnOnce(NodeView<'_>) -> R) -> R {
+        let node = self.get(*node);
+        f(NodeView {
+            kind: &node.kind,
+            name: &node.name,
+            namespace: node.namespace.as_ref(),
+            attributes: &node.attributes,
+        })
+    }
+
+    fn parent(&self, node: &NodeIndex) -> Option<NodeIndex> {
+        self.get(*node).parent
+    }
+
+    fn preceding_elements(&self, node: &NodeIndex) -> Vec<NodeIndex> {
+        let mut result: Vec<NodeIndex> = Vec::new();
+        // roots are only linked as siblings for iteration, not for selectors
+        if self.get(*node).parent.is_none() {
+            return result;
+        }
+
+        let mut current = self.get(*node).previous_sibling;
+        while let Some(index) = current {
+            if self.get(index).is_element() {
+                result.push(index);
+            }
+            current = self.get(index).previous_sibling;
+        }
+        result.reverse();
+        result
+    }
+}
+
+impl Iterator for Children<'_> {
+    type Item = NodeIndex;
+
+    fn next(&mut self) -> Option<NodeIndex> {
+        let current = self.next?;
+        self.next = self.document.get(current).next_sibling;
+        Some(current)
+    }
+}
+
+impl Iterator for Descendants<'_> {
+    type Item = NodeIndex;
+
+    fn next(&mut self) -> Option<NodeIndex> {
+        let current = self.next?;
+        let node = self.document.get(current);
+
+        self.next = match node.first_child {
+            Some(child) => Some(child),
+            None => {
+                // climb until a node with a next sibling, stopping at the root
+                let mut climbing = Some(current);
+                let mut found = None;
+                while let Some(index) = climbing {
+                    if index == self.root {
+                        break;
+                    }
+                    let climbed = self.document.get(index);
+                    if climbed.next_sibling.is_some() {
+                        found = climbed.next_sibling;
+                        break;
+                    }
+                    climbing = climbed.parent;
+                }
+                found
+            }
+        };
+
+        Some(current)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xml::{parse_nodes, IdStrategy};
+
+    const SOURCE: &str = r#"<Page id="page">
+        <Row id="r1"><Column id="c1"/>text<Column id="c2"><Text id="t1"/></Column></Row>
+        <!-- note -->
+        <Row id="r2" class="last"><Column id="c3"/></Row>
+    </Page><Footer id="footer"><Text id="t2"/></Footer>"#;
+
+    fn nodes(source: &str) -> Vec<NodeAsync> {
+        parse_nodes(&"test".into(), source, &IdStrategy::default()).unwrap().0
+    }
+
+    fn id_of(document: &XmlDocument, index: NodeIndex) -> String {
+        let node = document.get(index);
+        if node.is_element() {
+            node.get_attribute("Default", "id").unwrap().into()
+        } else {
+            node.name.clone()
+        }
+    }
+
+    fn ids(document: &XmlDocument, indices: impl IntoIterator<Item = NodeIndex>) -> Vec<String> {
+        indices.into_iter().map(|x| id_of(document, x)).collect()
+    }
+
+    fn find(document: &XmlDocument, id: &str) -> NodeIndex {
+        (0..document.len()).map(NodeIndex).find(|x| id_of(document, *x) == id).unwrap()
+    }
+
+    #[test]
+    fn descendants_climb_back_up_and_stop_at_the_root() {
+        let document = XmlDocument::from_nodes(&nodes(SOURCE));
+
+        assert_eq!(
+            ids(&document, document.descendants(document.roots()[0])),
+            ["page", "r1", "c1", "text-content", "c2", "t1", "#comment", "r2", "c3"]
+        );
+        // a subtree ends where it ends, even though later siblings follow
+        assert_eq!(ids(&document, document.descendants(find(&document, "c2"))), ["c2", "t1"]);
+        assert_eq!(ids(&document, document.descendants(find(&document, "c1"))), ["c1"]);
+        assert_eq!(ids(&document, document.descendants(document.roots()[1])), ["footer", "t2"]);
+        assert_eq!(ids(&document, document.get_leaves(find(&document, "r1"))), ["c1", "text-content", "t1"]);
+    }
+
+    #[test]
+    fn preceding_elements_skip_other_kinds_and_roots() {
+        let document = XmlDocument::from_nodes(&nodes(SOURCE));
+
+        assert_eq!(ids(&document, document.preceding_elements(&find(&document, "r2"))), ["r1"]);
+        assert_eq!(ids(&document, document.preceding_elements(&find(&document, "c2"))), ["c1"]);
+        assert!(document.preceding_elements(&find(&document, "c1")).is_empty());
+        assert!(document.preceding_elements(&find(&document, "footer")).is_empty());
+    }
+
+    #[test]
+    fn round_trip_keeps_order_and_parent_links() {
+        let roots = nodes(SOURCE);
+        let document = XmlDocument::from_nodes(&roots);
+        let copies = document.to_nodes();
+        assert_eq!(copies.len(), roots.len());
+
+        fn check(original: &NodeAsync, copy: &NodeAsync) {
+            let (original_guard, copy_guard) = (original.read(), copy.read());
+            assert!(!original.ptr_eq(copy));
+            assert_eq!(copy_guard.kind, original_guard.kind);
+            assert_eq!(copy_guard.name, original_guard.name);
+            assert_eq!(copy_guard.attributes, original_guard.attributes);
+            assert_eq!(copy_guard.children.len(), original_guard.children.len());
+            for (original_child, copy_child) in original_guard.children.iter().zip(copy_guard.children.iter()) {
+                assert!(copy_child.parent().unwrap().ptr_eq(copy));
+                check(original_child, copy_child);
+            }
+        }
+        for (original, copy) in roots.iter().zip(copies.iter()) {
+            assert!(copy.parent().is_none());
+            check(original, copy);
+        }
+
+        let again = XmlDocument::from_nodes(&copies);
+        let all = |x: &XmlDocument| -> Vec<String> {
+            x.roots().iter().flat_map(|root| ids(x, x.descendants(*root))).collect()
+        };
+        assert_eq!(all(&again), all(&document));
+    }
+
+    #[test]
+    fn selectors_match_the_same_nodes_as_on_the_tree() {
+        let roots = nodes(SOURCE);
+        let document = XmlDocument::from_nodes(&roots);
+
+        for selector in [
+            "Page > Row Column",
+            "Column + Column",
+            "Row ~ Row",
+            "Column:first-child",
+            "Row:nth-child(2)",
+            "Row:not(.last) > *",
+            "Page Text",
+            "Text",
+        ] {
+            let selector = NodeSelector::parse(selector).unwrap();
+            let mut matched = 0;
+            for (root, index) in roots.iter().zip(document.roots().iter()) {
+                let expected: Vec<String> = selector
+                    .apply(root.clone())
+                    .iter()
+                    .map(|x| x.read().get_attribute("Default", "id").unwrap())
+                    .collect();
+                assert_eq!(ids(&document, document.select(&selector, *index)), expected, "{selector:?}");
+                matched += expected.len();
+            }
+            assert!(matched > 0, "{selector:?}");
+        }
+    }
+}
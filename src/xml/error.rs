@@ -1,10 +1,19 @@
 use derive_more::From;
 
 use super::StoreIndex;
+pub use crate::error::SourceReadFailureContents;
+use crate::error::Location;
 
 #[derive(Debug)]
-pub struct SourceReadFailureContents {
-    pub entry_index: StoreIndex,
+pub struct ParseFailureContents {
+    pub location: Location,
+    pub error: xmltree::ParseError,
+}
+
+#[derive(Debug)]
+pub struct SelectorParseFailureContents {
+    pub selector: String,
+    pub column: usize,
     pub failure_message: String,
 }
 
@@ -13,23 +22,111 @@ pub enum Error {
     #[from]
     Native(xmltree::Error),
 
+    SourceReadFailure(Box<SourceReadFailureContents>),
+
+    ParseFailure(Box<ParseFailureContents>),
+
     #[from]
-    SourceReadFailure(SourceReadFailureContents),
+    SelectorParseFailure(SelectorParseFailureContents),
+    /// A comment or processing instruction, named by its path, which no
+    /// selector matches.
+    Unselectable(String),
 
     AlreadyInStore(StoreIndex),
+    MissingIndex(StoreIndex),
+    NotRendered(StoreIndex),
+    /// The entry and the id more than one of its nodes has, under
+    /// `DuplicateIdPolicy::Error`.
+    DuplicateId(StoreIndex, String),
+    /// A thread panicked while holding the store's or an entry's lock.
+    PoisonedLock,
+
+    // tree mutations, each naming the offending node by its path
+    CyclicInsertion(String),
+    NotAChild(String),
+    NotAnElement(String),
+    Detached(String),
+}
+
+impl ParseFailureContents {
+    /// Locates `error` in `source`, the text entry `index` was parsed from.
+    pub fn new(error: xmltree::ParseError, index: &StoreIndex, source: &str) -> Self {
+        let mut location = Location::new(Some(index.clone()), None);
+        if let xmltree::ParseError::MalformedXml(err) = &error {
+            let position = xml_rs::common::Position::position(err);
+            location = location.at(source, position.row as usize + 1, Some(position.column as usize + 1));
+        }
+        Self { location, error }
+    }
+}
+
+impl Error {
+    pub fn location(&self) -> Option<&Location> {
+        match self {
+            Self::SourceReadFailure(contents) => Some(&contents.location),
+            Self::ParseFailure(contents) => Some(&contents.location),
+            _ => None,
+        }
+    }
 }
 
-impl std::fmt::Display for SourceReadFailureContents {
+impl<G> From<std::sync::PoisonError<G>> for Error {
+    fn from(_: std::sync::PoisonError<G>) -> Self {
+        Self::PoisonedLock
+    }
+}
+
+impl std::fmt::Display for ParseFailureContents {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.error {
+            // the position is already part of the location
+            xmltree::ParseError::MalformedXml(err) => write!(f, "{}: {}", self.location, err.msg()),
+            err => write!(f, "{}: {}", self.location, err),
+        }
+    }
+}
+
+impl std::fmt::Display for SelectorParseFailureContents {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.failure_message)
+        write!(f, "'{}' column {}: {}", self.selector, self.column, self.failure_message)
     }
 }
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{self:?}")
+        match self {
+            Self::Native(err) => write!(f, "{err}"),
+            Self::SourceReadFailure(contents) => write!(f, "could not read XML {contents}"),
+            Self::ParseFailure(contents) => write!(f, "could not parse {contents}"),
+            Self::SelectorParseFailure(contents) => write!(f, "could not parse selector {contents}"),
+            Self::Unselectable(node) => write!(f, "{node} is not an element or text and has no selector"),
+            Self::AlreadyInStore(index) => write!(f, "XML entry '{index}' is already in the store"),
+            Self::MissingIndex(index) => write!(f, "no XML entry at index '{index}'"),
+            Self::NotRendered(index) => write!(f, "XML entry '{index}' was not rendered from a template"),
+            Self::DuplicateId(index, id) => write!(f, "XML entry '{index}' has more than one node with id '{id}'"),
+            Self::PoisonedLock => write!(f, "an XML store lock was poisoned"),
+            Self::CyclicInsertion(node) => write!(f, "inserting {node} would make it its own ancestor"),
+            Self::NotAChild(node) => write!(f, "{node} is not a child of the node"),
+            Self::NotAnElement(node) => write!(f, "{node} is not an element and cannot have children"),
+            Self::Detached(node) => write!(f, "{node} has no parent"),
+        }
+    }
+}
+
+impl std::error::Error for ParseFailureContents {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
     }
 }
+impl std::error::Error for SelectorParseFailureContents {}
 
-impl std::error::Error for SourceReadFailureContents {}
-impl std::error::Error for Error {}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Native(err) => Some(err),
+            Self::SourceReadFailure(contents) => Some(&contents.error),
+            Self::ParseFailure(contents) => Some(&contents.error),
+            _ => None,
+        }
+    }
+}
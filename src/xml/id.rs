@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::vec::Vec;
+
+use super::serialize::is_generated_id;
+use super::{NodeAsync, NodeId, NodeKind, XmlNode};
+
+/// Marks an `id` attribute as generated rather than user-supplied. User ids
+/// should not start with it.
+pub const GENERATED_ID_PREFIX: &str = "pk-";
+
+type Callback = Arc<dyn Fn(&XmlNode, &str) -> String + Send + Sync>;
+
+/// How `XmlStore` names the nodes that have no `id` of their own when it
+/// parses an entry. Every strategy but `RandomUuid` gives the same ids to
+/// the same document on every run, and a re-render gives the nodes it keeps
+/// the ids a fresh parse would. Nodes kept under `RandomUuid` keep theirs.
+#[derive(Clone, Default)]
+pub enum IdStrategy {
+    /// `pk-<uuid v4>`.
+    #[default]
+    RandomUuid,
+    /// `pk-` and the node's path from its root, like
+    /// `pk-Container/Row[0]/Column[1]`, each step counting the siblings of
+    /// the same name. A root is only counted when other roots share its name.
+    Path,
+    /// `pk-` and a hash of the node's name, user-supplied attributes and
+    /// contents. Identical subtrees get `-1`, `-2`, ... after the first.
+    ContentHash,
+    /// `pk-` and whatever the callback makes of the node and its `Path`.
+    Callback(Callback),
+}
+
+impl IdStrategy {
+    pub fn callback(callback: impl Fn(&XmlNode, &str) -> String + Send + Sync + 'static) -> Self {
+        Self::Callback(Arc::new(callback))
+    }
+}
+
+impl std::fmt::Debug for IdStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::RandomUuid => write!(f, "RandomUuid"),
+            Self::Path => write!(f, "Path"),
+            Self::ContentHash => write!(f, "ContentHash"),
+            Self::Callback(_) => write!(f, "Callback(..)"),
+        }
+    }
+}
+
+pub(super) fn random_id() -> String {
+    format!("{GENERATED_ID_PREFIX}{}", uuid::Uuid::new_v4())
+}
+
+/// Replaces the generated ids below `roots` with the ones `strategy` gives.
+/// Returns the nodes whose id changed, with the new id.
+pub(super) fn assign(strategy: &IdStrategy, roots: &[NodeAsync]) -> Vec<(NodeAsync, String)> {
+    let mut result: Vec<(NodeAsync, String)> = Vec::new();
+    if let IdStrategy::RandomUuid = strategy {
+        return result;
+    }
+
+    let mut hashes: HashMap<NodeId, u64> = HashMap::new();
+    if let IdStrategy::ContentHash = strategy {
+        for root in roots.iter() {
+            hash_subtree(root, &mut hashes);
+        }
+    }
+    let mut repeats: HashMap<u64, usize> = HashMap::new();
+
+    for (node, path) in paths(roots).into_iter() {
+        let mut node_guard = node.write();
+        let current = node_guard.get_attribute("Default", "id");
+        if !current.as_deref().is_none_or(is_generated_id) {
+            continue;
+        }
+
+        let name = match strategy {
+            IdStrategy::RandomUuid => continue,
+            IdStrategy::Path => path,
+            IdStrategy::ContentHash => {
+                let hash = hashes[&node.node_id()];
+                let repeat = repeats.entry(hash).or_default();
+                *repeat += 1;
+                match *repeat {
+                    1 => format!("{hash:016x}"),
+                    repeat => format!("{hash:016x}-{}", repeat - 1),
+                }
+            }
+            IdStrategy::Callback(callback) => callback(&node_guard, &path),
+        };
+        let id = format!("{GENERATED_ID_PREFIX}{name}");
+        if current.as_ref() != Some(&id) {
+            node_guard.attributes.insert(("Default".into(), "id".into()), id.clone());
+            drop(node_guard);
+            result.push((node, id));
+        }
+    }
+    result
+}
+
+/// Every node below `roots` with its path, in document order.
+fn paths(roots: &[NodeAsync]) -> Vec<(NodeAsync, String)> {
+    let mut result: Vec<(NodeAsync, String)> = Vec::new();
+    for (root, step) in roots.iter().zip(steps(roots, true)) {
+        visit(root, step, &mut result);
+    }
+    result
+}
+
+fn visit(node: &NodeAsync, path: String, result: &mut Vec<(NodeAsync, String)>) {
+    let children = node.read().children.clone();
+    result.push((node.clone(), path.clone()));
+    for (child, step) in children.iter().zip(steps(&children, false)) {
+        visit(child, format!("{path}/{step}"), result);
+    }
+}
+
+/// `Name[i]` for each of `siblings`, `i` counting the earlier siblings of
+/// the same name. Roots only get the count when their name is shared.
+fn steps(siblings: &[NodeAsync], roots: bool) -> Vec<String> {
+    let names: Vec<String> = siblings.iter().map(|x| x.read().name.clone()).collect();
+    let mut seen: HashMap<&str, usize> = HashMap::new();
+    let mut result: Vec<String> = Vec::new();
+    for name in names.iter() {
+        let count = seen.entry(name).or_default();
+        let shared = !roots || names.iter().filter(|x| *x == name).count() > 1;
+        result.push(if shared { format!("{name}[{count}]") } else { name.clone() });
+        *count += 1;
+    }
+    result
+}
+
+/// 64-bit FNV-1a, which unlike the standard library's hasher is the same
+/// on every platform and release.
+fn fnv(hash: &mut u64, bytes: &[u8]) {
+    for byte in bytes.iter() {
+        *hash ^= *byte as u64;
+        *hash = hash.wrapping_mul(0x100000001b3);
+    }
+}
+
+fn hash_subtree(node: &NodeAsync, hashes: &mut HashMap<NodeId, u64>) -> u64 {
+    let node_guard = node.read();
+    let mut hash: u64 = 0xcbf29ce484222325;
+
+    let (tag, content): (&[u8], &str) = match &node_guard.kind {
+        NodeKind::Element => (b"e", ""),
+        NodeKind::Text(text) => (b"t", text),
+        NodeKind::CData(text) => (b"c", text),
+        NodeKind::Comment(text) => (b"m", text),
+        NodeKind::ProcessingInstruction { data, .. } => (b"p", data.as_deref().unwrap_or_default()),
+    };
+    for part in [tag, node_guard.name.as_bytes(), content.as_bytes()] {
+        fnv(&mut hash, part);
+        fnv(&mut hash, &[0]);
+    }
+
+    let mut attributes: Vec<(&(String, String), &String)> = node_guard
+        .attributes
+        .iter()
+        .filter(|((namespace, name), value)| !(namespace == "Default" && name == "id" && is_generated_id(value)))
+        .collect();
+    attributes.sort();
+    for ((namespace, name), value) in attributes.into_iter() {
+        for part in [namespace, name, value] {
+            fnv(&mut hash, part.as_bytes());
+            fnv(&mut hash, &[0]);
+        }
+    }
+
+    for child in node_guard.children.iter() {
+        fnv(&mut hash, &hash_subtree(child, hashes).to_le_bytes());
+    }
+
+    hashes.insert(node.node_id(), hash);
+    hash
+}
@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::vec::Vec;
+
+use super::class::class_key;
+use super::observe::NodeEvent;
+use super::{Namespace, NodeAsync, NodeId, StoreIndex, XmlNode};
+
+/// How an `XmlStore` treats two nodes of one entry that share an id.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DuplicateIdPolicy {
+    /// Keep the entry, pass the ids to the store's `on_duplicate_ids` and
+    /// list them in `StoreEntry::duplicate_ids`.
+    #[default]
+    Warn,
+    /// Refuse to parse or re-render an entry into a tree with duplicate ids.
+    /// Mutations cannot be refused once made, so duplicates they introduce
+    /// are only listed in `StoreEntry::duplicate_ids`.
+    Error,
+}
+
+type WarningCallback = Arc<dyn Fn(&StoreIndex, &[String]) + Send + Sync>;
+
+/// Called with an entry's index and its duplicate ids, sorted, whenever the
+/// entry is parsed or re-rendered into a tree that has some under
+/// `DuplicateIdPolicy::Warn`. The store or entry is locked while it runs, so
+/// it must not use them.
+#[derive(Clone)]
+pub struct DuplicateIdWarning(WarningCallback);
+
+/// The nodes of one entry by the values of one attribute, kept current by an
+/// observer on each of the entry's roots. A node listed under a value keeps
+/// its place as others are added, so the first is the earliest indexed.
+#[derive(Debug)]
+pub(super) struct AttributeIndex {
+    key: (Namespace, String),
+    values_of: fn(&XmlNode) -> Vec<String>,
+    by_value: HashMap<String, Vec<NodeAsync>>,
+    by_node: HashMap<NodeId, Vec<String>>,
+}
+
+impl DuplicateIdWarning {
+    pub fn new(callback: impl Fn(&StoreIndex, &[String]) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(callback))
+    }
+
+    pub(super) fn warn(&self, index: &StoreIndex, ids: &[String]) {
+        (self.0)(index, ids)
+    }
+}
+
+impl std::fmt::Debug for DuplicateIdWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "DuplicateIdWarning(..)")
+    }
+}
+
+impl AttributeIndex {
+    /// Elements by each of their classes.
+    pub(super) fn classes(roots: &[NodeAsync]) -> Self {
+        fn values_of(node: &XmlNode) -> Vec<String> {
+            if node.is_element() {
+                node.classes()
+            } else {
+                Vec::new()
+            }
+        }
+        Self::build(class_key(), values_of, roots)
+    }
+
+    /// Nodes of any kind by id.
+    pub(super) fn ids(roots: &[NodeAsync]) -> Self {
+        fn values_of(node: &XmlNode) -> Vec<String> {
+            node.get_attribute("Default", "id").into_iter().collect()
+        }
+        Self::build(("Default".into(), "id".into()), values_of, roots)
+    }
+
+    fn build(key: (Namespace, String), values_of: fn(&XmlNode) -> Vec<String>, roots: &[NodeAsync]) -> Self {
+        let mut result = Self {
+            key,
+            values_of,
+            by_value: HashMap::new(),
+            by_node: HashMap::new(),
+        };
+        for root in roots.iter() {
+            result.insert_subtree(root);
+        }
+        result
+    }
+
+    pub(super) fn get(&self, value: &str) -> &[NodeAsync] {
+        self.by_value.get(value).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    /// Values more than one node is indexed under, sorted.
+    pub(super) fn duplicates(&self) -> Vec<String> {
+        let mut result: Vec<String> =
+            self.by_value.iter().filter(|(_, nodes)| nodes.len() > 1).map(|(value, _)| value.clone()).collect();
+        result.sort();
+        result
+    }
+
+    pub(super) fn apply(&mut self, event: &NodeEvent) {
+        match event {
+            NodeEvent::AttributeChanged { node, key, .. } if *key == self.key => self.insert(node),
+            NodeEvent::ChildInserted { child, .. } => self.insert_subtree(child),
+            NodeEvent::ChildRemoved { child, .. } => self.remove_subtree(child),
+            _ => {}
+        }
+    }
+
+    /// Indexes `node` under its current values, replacing whatever it was
+    /// indexed under before. A node whose values have not changed keeps its
+    /// place.
+    fn insert(&mut self, node: &NodeAsync) {
+        let values = (self.values_of)(&node.read());
+        if self.by_node.get(&node.node_id()) == Some(&values) {
+            return;
+        }
+        self.remove(node);
+        if values.is_empty() {
+            return;
+        }
+
+        for value in values.iter() {
+            self.by_value.entry(value.clone()).or_default().push(node.clone());
+        }
+        self.by_node.insert(node.node_id(), values);
+    }
+
+    fn remove(&mut self, node: &NodeAsync) {
+        for value in self.by_node.remove(&node.node_id()).unwrap_or_default() {
+            if let Some(nodes) = self.by_value.get_mut(&value) {
+                nodes.retain(|x| !x.ptr_eq(node));
+                if nodes.is_empty() {
+                    self.by_value.remove(&value);
+                }
+            }
+        }
+    }
+
+    /// Indexes `root` and everything below it in document order.
+    fn insert_subtree(&mut self, root: &NodeAsync) {
+        let mut pending: Vec<NodeAsync> = vec![root.clone()];
+        while let Some(node) = pending.pop() {
+            self.insert(&node);
+            pending.extend(node.read().children.iter().rev().cloned());
+        }
+    }
+
+    fn remove_subtree(&mut self, root: &NodeAsync) {
+        let mut pending: Vec<NodeAsync> = vec![root.clone()];
+        while let Some(node) = pending.pop() {
+            self.remove(&node);
+            pending.extend(node.read().children.iter().cloned());
+        }
+    }
+}
@@ -1,23 +1,52 @@
+mod class;
+pub mod diff;
+pub mod document;
 mod error;
+mod id;
+mod index;
+mod mutate;
+pub mod observe;
+pub mod select;
+pub mod serialize;
 
 use xmltree;
 
-use std::cell::OnceCell;
 use std::collections::{BTreeMap, HashMap};
-use std::ops::Deref;
-use std::sync::{Arc, RwLock, Weak};
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard, Weak};
 use std::vec::Vec;
 
 use crate::template;
 use crate::AsyncHandle;
+use index::AttributeIndex;
 
-pub use error::{Error, SourceReadFailureContents};
+pub use diff::{NodeChange, Patch};
+pub use document::{NodeIndex, XmlDocument};
+pub use error::{Error, ParseFailureContents, SelectorParseFailureContents, SourceReadFailureContents};
+pub use id::{IdStrategy, GENERATED_ID_PREFIX};
+pub use index::{DuplicateIdPolicy, DuplicateIdWarning};
+pub use observe::{NodeEvent, Observers, Scope, SubscriptionId};
+pub use serialize::SerializeOptions;
 
 pub type StoreIndex = String;
 pub type Namespace = String;
 
+/// Name given to text and CDATA nodes, matching the `text-content` elements
+/// they used to be parsed into.
+pub const TEXT_CONTENT: &str = "text-content";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NodeKind {
+    Element,
+    Text(String),
+    CData(String),
+    Comment(String),
+    ProcessingInstruction { target: String, data: Option<String> },
+}
+
 #[derive(Debug)]
 pub struct XmlNode {
+    pub kind: NodeKind,
+
     pub prefix: Option<String>,
     pub namespace: Option<Namespace>,
     pub namespaces: Option<BTreeMap<String, String>>,
@@ -27,27 +56,127 @@ pub struct XmlNode {
 
     pub children: Vec<NodeAsync>,
     pub parent: Option<Weak<RwLock<XmlNode>>>,
+    /// Set on the nodes an `include_tree` call spliced in, naming the
+    /// template entry they were rendered from.
+    pub included_from: Option<StoreIndex>,
+
+    pub observers: Observers,
 }
 
 #[derive(Debug, Clone)]
 pub struct NodeAsync(AsyncHandle<XmlNode>);
 
+/// Identity of a `NodeAsync`, shared by every handle to the same node. Only
+/// meaningful while the node is alive; the value of a dropped node may be
+/// handed to a new one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeId(usize);
+
 #[derive(Debug)]
 pub struct StoreEntry {
     pub store: Weak<RwLock<XmlStore>>,
     pub index: StoreIndex,
+    /// The root elements, in document order.
     pub nodes: Arc<[NodeAsync]>,
+    /// The comments and processing instructions outside `nodes`, each with
+    /// the number of roots before it, so `to_xml` can write them in place.
+    pub misc: Arc<[(usize, NodeAsync)]>,
     pub source: String,
+    pub origin: Option<RenderOrigin>,
+    class_index: AsyncHandle<AttributeIndex>,
+    id_index: AsyncHandle<AttributeIndex>,
+    /// The observers on `nodes` that keep the indices current.
+    subscriptions: Vec<(NodeAsync, SubscriptionId)>,
+    duplicate_id_policy: DuplicateIdPolicy,
+    on_duplicate_ids: Option<DuplicateIdWarning>,
+    id_strategy: IdStrategy,
 }
 pub type StoreEntryAsync = AsyncHandle<StoreEntry>;
 
+/// The template and context an entry's `source` was rendered from.
+#[derive(Debug, Clone)]
+pub struct RenderOrigin {
+    pub template: template::StoreEntryAsync<'static>,
+    pub context: minijinja::Value,
+    /// The template's version as of the last render.
+    pub version: u64,
+    /// The versions of the templates it depended on as of the last render,
+    /// from `TemplateStore::dependency_versions`.
+    pub dependencies: BTreeMap<StoreIndex, u64>,
+}
+
 #[derive(Debug, Clone)]
 pub struct XmlStore {
     pub indices: AsyncHandle<HashMap<StoreIndex, StoreEntryAsync>>,
-    handle: OnceCell<Arc<RwLock<Self>>>,
+    /// Applies to entries added after it is set.
+    pub duplicate_id_policy: DuplicateIdPolicy,
+    /// Applies to entries added after it is set.
+    pub on_duplicate_ids: Option<DuplicateIdWarning>,
+    /// Applies to entries added after it is set.
+    pub id_strategy: IdStrategy,
+    /// Weak, so the store does not keep itself alive.
+    handle: Weak<RwLock<Self>>,
 }
 
 impl XmlNode {
+    fn new(kind: NodeKind, name: String) -> Self {
+        let mut attributes: HashMap<(Namespace, String), String> = HashMap::new();
+        attributes.insert(("Default".into(), "id".into()), id::random_id());
+
+        Self {
+            kind,
+
+            prefix: None,
+            namespace: None,
+            namespaces: None,
+
+            name,
+            attributes,
+
+            children: Vec::default(),
+            parent: None,
+            included_from: None,
+
+            observers: Observers::default(),
+        }
+    }
+
+    pub fn is_element(&self) -> bool {
+        self.kind == NodeKind::Element
+    }
+
+    /// Text and CDATA nodes, which both read as character data.
+    pub fn is_text(&self) -> bool {
+        matches!(self.kind, NodeKind::Text(_) | NodeKind::CData(_))
+    }
+
+    pub fn text(&self) -> Option<&str> {
+        match &self.kind {
+            NodeKind::Text(content) | NodeKind::CData(content) => Some(content),
+            _ => None,
+        }
+    }
+
+    /// The `text-content` element this node used to be parsed into: trimmed
+    /// text in a `content` attribute next to the node's `id`.
+    pub fn as_text_content(&self) -> Option<XmlNode> {
+        let content = self.text()?;
+
+        let mut result = XmlNode::new(NodeKind::Element, TEXT_CONTENT.into());
+        result.namespace = Some("Default".into());
+        result.attributes = self
+            .attributes
+            .iter()
+            .filter(|((namespace, name), _)| namespace == "Default" && name == "id")
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        result
+            .attributes
+            .insert(("Default".into(), "content".into()), content.trim().to_string());
+        result.parent = self.parent.clone();
+        Some(result)
+    }
+
     pub fn has_attribute(&self, namespace: &str, attribute: &str) -> bool {
         let key = &(namespace.into(), attribute.into());
         self.attributes.contains_key(key)
@@ -60,14 +189,36 @@ impl XmlNode {
 }
 
 impl NodeAsync {
-    pub fn to_ptr(&self) -> *const XmlNode {
-        let arc = &self.0;
-        let node_ptr = Arc::as_ptr(arc);
+    pub fn node_id(&self) -> NodeId {
+        NodeId(Arc::as_ptr(&self.0) as usize)
+    }
+
+    pub fn ptr_eq(&self, other: &NodeAsync) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+
+    /// The link a child keeps to this node as its `parent`.
+    fn downgrade(&self) -> Weak<RwLock<XmlNode>> {
+        Arc::downgrade(&self.0)
+    }
 
-        // UNSAFE: dereference arc to get raw pointer to Node
-        // this assumes the Arc is not dropped while being used.
-        let node_ref = unsafe { (*node_ptr).read().unwrap() };
-        &*node_ref as *const XmlNode
+    pub fn try_read(&self) -> Result<RwLockReadGuard<'_, XmlNode>, Error> {
+        Ok(self.0.read()?)
+    }
+
+    pub fn try_write(&self) -> Result<RwLockWriteGuard<'_, XmlNode>, Error> {
+        Ok(self.0.write()?)
+    }
+
+    /// Node locks are taken inside the crate by accessors that have no error
+    /// to return, and no node is left half-updated across anything that can
+    /// panic, so there a poisoned node lock is read through.
+    pub(crate) fn read(&self) -> RwLockReadGuard<'_, XmlNode> {
+        self.0.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub(crate) fn write(&self) -> RwLockWriteGuard<'_, XmlNode> {
+        self.0.write().unwrap_or_else(PoisonError::into_inner)
     }
 
     pub fn get_leaves(&self) -> Arc<[Self]> {
@@ -75,7 +226,7 @@ impl NodeAsync {
         let mut leaves: Vec<Self> = vec![];
 
         while let Some(node) = stack.pop() {
-            let node_guard = node.read().unwrap();
+            let node_guard = node.read();
             if node_guard.children.is_empty() {
                 leaves.push(node.clone());
             } else {
@@ -87,35 +238,284 @@ impl NodeAsync {
 
         leaves[..].into()
     }
+
+    pub fn to_xml(&self, options: &SerializeOptions) -> String {
+        let mut result = String::new();
+        serialize::write_nodes(&mut result, std::slice::from_ref(self), options).unwrap();
+        result
+    }
+
+    /// Sets an attribute and notifies observers. Returns the previous value.
+    pub fn set_attribute(&self, namespace: &str, attribute: &str, value: String) -> Option<String> {
+        let key: (Namespace, String) = (namespace.into(), attribute.into());
+        let previous = self.write().attributes.insert(key.clone(), value.clone());
+
+        if previous.as_ref() != Some(&value) {
+            observe::dispatch(vec![NodeEvent::AttributeChanged {
+                node: self.clone(),
+                key,
+                value: Some(value),
+            }]);
+        }
+        previous
+    }
+
+    /// Removes an attribute and notifies observers. Returns the removed value.
+    pub fn remove_attribute(&self, namespace: &str, attribute: &str) -> Option<String> {
+        let key: (Namespace, String) = (namespace.into(), attribute.into());
+        let previous = self.write().attributes.remove(&key);
+
+        if previous.is_some() {
+            observe::dispatch(vec![NodeEvent::AttributeChanged {
+                node: self.clone(),
+                key,
+                value: None,
+            }]);
+        }
+        previous
+    }
+
+    /// Replaces the contents of a text or CDATA node and notifies observers.
+    /// Returns the previous contents, or `None` without changing anything if
+    /// this is not a text node.
+    pub fn set_text(&self, content: String) -> Option<String> {
+        let (previous, kind) = {
+            let mut node_guard = self.write();
+            let previous = match &mut node_guard.kind {
+                NodeKind::Text(current) | NodeKind::CData(current) => std::mem::replace(current, content.clone()),
+                _ => return None,
+            };
+            (previous, node_guard.kind.clone())
+        };
+
+        if previous != content {
+            observe::dispatch(vec![NodeEvent::TextChanged {
+                node: self.clone(),
+                kind,
+            }]);
+        }
+        Some(previous)
+    }
 }
 
-impl Deref for NodeAsync {
-    type Target = Arc<RwLock<XmlNode>>;
+impl RenderOrigin {
+    pub fn render(&mut self) -> crate::Result<String> {
+        // taken before rendering, so a dependency changing in between leaves
+        // the entry stale rather than unnoticed
+        let dependencies = self.current_dependencies()?;
+        let template_guard = self.template.read().map_err(template::Error::from)?;
+        let rendered = template_guard.render(self.context.clone())?;
+        self.version = template_guard.version();
+        self.dependencies = dependencies;
+        Ok(rendered)
+    }
 
-    fn deref(&self) -> &Self::Target {
-        &self.0
+    /// Whether the template or anything it depends on has changed since the
+    /// last render.
+    pub fn is_stale(&self) -> crate::Result<bool> {
+        if self.template.read().map_err(template::Error::from)?.version() != self.version {
+            return Ok(true);
+        }
+        Ok(self.current_dependencies()? != self.dependencies)
+    }
+
+    fn current_dependencies(&self) -> crate::Result<BTreeMap<StoreIndex, u64>> {
+        // the store locks its indices before any entry, so the template's
+        // own lock is let go first
+        let (store, index) = {
+            let template_guard = self.template.read().map_err(template::Error::from)?;
+            (template_guard.store()?, template_guard.index().clone())
+        };
+        let store_guard = store.read().map_err(template::Error::from)?;
+        store_guard.dependency_versions(&index)
     }
 }
 
-impl std::fmt::Display for NodeAsync {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+impl StoreEntry {
+    /// Writes `nodes` with `misc` back in its place between them.
+    pub fn to_xml(&self, options: &SerializeOptions) -> String {
+        let mut roots: Vec<NodeAsync> = Vec::new();
+        let mut misc = self.misc.iter().peekable();
+        for position in 0..=self.nodes.len() {
+            while let Some((_, node)) = misc.next_if(|(before, _)| *before <= position) {
+                roots.push(node.clone());
+            }
+            roots.extend(self.nodes.get(position).cloned());
+        }
+
         let mut result = String::new();
+        serialize::write_nodes(&mut result, &roots, options).unwrap();
+        result
+    }
 
-        {
-            result += &self.read().unwrap().name;
+    /// Whether the entry was rendered from template `template`, from one
+    /// that extends, includes or imports it directly or not, or has nodes
+    /// spliced in from it by `include_tree`.
+    pub fn depends_on(&self, template: &StoreIndex) -> crate::Result<bool> {
+        if let Some(origin) = &self.origin {
+            let template_guard = origin.template.read().map_err(template::Error::from)?;
+            if template_guard.index() == template || origin.dependencies.contains_key(template) {
+                return Ok(true);
+            }
         }
 
-        // TODO: more
+        let mut pending: Vec<NodeAsync> = self.nodes.to_vec();
+        while let Some(node) = pending.pop() {
+            let node_guard = node.try_read()?;
+            if node_guard.included_from.as_ref() == Some(template) {
+                return Ok(true);
+            }
+            pending.extend(node_guard.children.iter().cloned());
+        }
+        Ok(false)
+    }
+
+    /// Whether the template, or a template it depends on, has changed since
+    /// the entry was last rendered. Always `false` for entries that were not
+    /// rendered.
+    pub fn is_stale(&self) -> crate::Result<bool> {
+        match &self.origin {
+            Some(origin) => origin.is_stale(),
+            None => Ok(false),
+        }
+    }
+
+    /// Renders the entry's template again with `context` and patches the
+    /// existing tree to match, so unchanged nodes keep their identity. Nodes
+    /// are paired by user-supplied `id`, or by position among siblings of the
+    /// same name when they only have a generated one. Generated ids are then
+    /// given again by the entry's `IdStrategy`, and the patched tree checked
+    /// for duplicate ids.
+    ///
+    /// Takes the entry's handle rather than a guard so that observers hear
+    /// about the patches only once the entry's lock is released.
+    pub fn rerender(entry: &StoreEntryAsync, context: minijinja::Value) -> crate::Result<Vec<Patch>> {
+        let mut entry_guard = entry.write().map_err(Error::from)?;
+        let patches = entry_guard.patch_to_render(context)?;
+        let checked = entry_guard.check_duplicate_ids();
+        drop(entry_guard);
+
+        // the tree is patched either way, so observers hear of it either way
+        observe::dispatch(diff::events(&patches));
+        checked?;
+        Ok(patches)
+    }
+
+    /// The work of `rerender`, leaving the events and the duplicate id check
+    /// to it.
+    fn patch_to_render(&mut self, context: minijinja::Value) -> crate::Result<Vec<Patch>> {
+        let mut origin = match &self.origin {
+            Some(origin) => RenderOrigin {
+                template: origin.template.clone(),
+                context,
+                version: origin.version,
+                dependencies: origin.dependencies.clone(),
+            },
+            None => return Err(Error::NotRendered(self.index.clone()).into()),
+        };
+
+        let source = origin.render()?;
+        let (nodes, misc) = parse_nodes(&self.index, &source, &self.id_strategy)?;
+        // the patched tree ends up with the same ids, so refuse it before
+        // anything changes
+        if self.duplicate_id_policy == DuplicateIdPolicy::Error {
+            if let Some(id) = AttributeIndex::ids(&nodes).duplicates().into_iter().next() {
+                return Err(Error::DuplicateId(self.index.clone(), id).into());
+            }
+        }
 
-        let parent: &Option<Weak<RwLock<XmlNode>>> = &self.read().unwrap().parent;
-        if parent.is_some() {
-            let parent_handle = parent.as_ref().unwrap();
-            let parent_turned: NodeAsync = parent_handle.upgrade().unwrap().into();
-            let parent_str = parent_turned.to_string();
-            result = format!("{} > {}", parent_str, result);
+        let mut patches: Vec<Patch> = Vec::new();
+        let nodes = diff::reconcile(None, &self.nodes, nodes, &mut patches);
+        // kept nodes still have the generated ids of the old tree
+        for (node, id) in id::assign(&self.id_strategy, &nodes).into_iter() {
+            patches.push(Patch::Update {
+                node,
+                change: NodeChange::Attribute {
+                    key: ("Default".into(), "id".into()),
+                    value: Some(id),
+                },
+            });
         }
 
-        write!(f, "{}", result)
+        self.nodes = nodes[..].into();
+        self.misc = misc[..].into();
+        self.source = source;
+        self.origin = Some(origin);
+
+        // the roots may have changed, and with them what is observed; the
+        // indices take the events that follow without indexing anything twice
+        self.index_nodes();
+        Ok(patches)
+    }
+
+    /// Every element in the entry with class `class`, in no particular
+    /// order. Kept current as the tree changes through `NodeAsync`'s
+    /// methods; changes made directly on an `XmlNode` are not seen.
+    pub fn get_elements_by_class_name(&self, class: &str) -> Vec<NodeAsync> {
+        self.class_index.read().unwrap_or_else(PoisonError::into_inner).get(class).to_vec()
+    }
+
+    /// The node in the entry with id `id`, kept current the same way as
+    /// `get_elements_by_class_name`. When several share it, the first in
+    /// document order at parse time, or the earliest added since.
+    pub fn get_element_by_id(&self, id: &str) -> Option<NodeAsync> {
+        self.id_index.read().unwrap_or_else(PoisonError::into_inner).get(id).first().cloned()
+    }
+
+    /// Ids shared by more than one node of the entry right now, sorted.
+    pub fn duplicate_ids(&self) -> Vec<String> {
+        self.id_index.read().unwrap_or_else(PoisonError::into_inner).duplicates()
+    }
+
+    /// Fails under `DuplicateIdPolicy::Error` if the entry has duplicate ids,
+    /// and otherwise passes them to `on_duplicate_ids`.
+    fn check_duplicate_ids(&self) -> Result<(), Error> {
+        let duplicates = self.duplicate_ids();
+        if duplicates.is_empty() {
+            return Ok(());
+        }
+        match self.duplicate_id_policy {
+            DuplicateIdPolicy::Error => Err(Error::DuplicateId(self.index.clone(), duplicates[0].clone())),
+            DuplicateIdPolicy::Warn => {
+                if let Some(warning) = &self.on_duplicate_ids {
+                    warning.warn(&self.index, &duplicates);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Builds the indices over `nodes` from scratch and observes the roots to
+    /// keep them current.
+    fn index_nodes(&mut self) {
+        for (node, id) in self.subscriptions.drain(..) {
+            node.unsubscribe(id);
+        }
+
+        *self.class_index.write().unwrap_or_else(PoisonError::into_inner) = AttributeIndex::classes(&self.nodes);
+        *self.id_index.write().unwrap_or_else(PoisonError::into_inner) = AttributeIndex::ids(&self.nodes);
+
+        for root in self.nodes.iter() {
+            // weak handles, since the entry owns the nodes holding the observer
+            let indices = [Arc::downgrade(&self.class_index), Arc::downgrade(&self.id_index)];
+            let id = root.subscribe(Scope::Subtree, move |event| {
+                for index in indices.iter().filter_map(Weak::upgrade) {
+                    index.write().unwrap_or_else(PoisonError::into_inner).apply(event);
+                }
+            });
+            self.subscriptions.push((root.clone(), id));
+        }
+    }
+}
+
+/// The node's `Parent > Child` path from its root, written as a selector
+/// that `NodeSelector::parse` reads back.
+impl std::fmt::Display for NodeAsync {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        if let Some(parent) = self.parent() {
+            write!(f, "{parent} > ")?;
+        }
+        select::write_identifier(f, &self.read().name)
     }
 }
 
@@ -157,8 +557,8 @@ impl From<xmltree::Element> for NodeAsync {
             Some(namespace) => {
                 let mut result: Option<String> = None;
 
-                if namespaces.is_some() {
-                    for (key, value) in namespaces.as_ref().unwrap().iter() {
+                if let Some(namespaces) = &namespaces {
+                    for (key, value) in namespaces.iter() {
                         if *value == namespace {
                             result = Some(key.into());
                             break;
@@ -180,9 +580,11 @@ impl From<xmltree::Element> for NodeAsync {
             }
             attributes.insert((namespace.into(), name.into()), v);
         }
-        attributes.entry(("Default".into(), "id".into())).or_insert_with(|| format!("pk-{}", uuid::Uuid::new_v4()));
+        attributes.entry(("Default".into(), "id".into())).or_insert_with(id::random_id);
 
         let node: NodeAsync = Self(Arc::new(RwLock::new(XmlNode {
+            kind: NodeKind::Element,
+
             prefix: native_node.prefix,
             namespace,
             namespaces,
@@ -192,100 +594,141 @@ impl From<xmltree::Element> for NodeAsync {
 
             children: Vec::default(),
             parent: None,
-        })));
+            included_from: None,
 
-        let mut children: Vec<NodeAsync> = Vec::new();
+            observers: Observers::default(),
+        })));
 
-        for child in native_node.children.iter() {
-            match child {
-                xmltree::XMLNode::Text(content) => {
-                    let mut text_element = xmltree::Element::new("text-content");
-                    text_element.namespace = Some("Default".into());
-                    text_element.attributes.insert("id".into(), format!("pk-{}", uuid::Uuid::new_v4()));
-                    text_element.attributes.insert("content".into(), content.trim().to_string());
+        let mut children: Vec<NodeAsync> = convert_nodes(native_node.children, None);
 
-                    let child_turned = NodeAsync::from(text_element);
-                    child_turned.write().unwrap().parent = Some(Arc::downgrade(&node.0));
-                    children.push(child_turned);
-                }
-                xmltree::XMLNode::Element(element) => {
-                    let child_turned = NodeAsync::from(element.clone());
-                    child_turned.write().unwrap().parent = Some(Arc::downgrade(&node.0));
-                    children.push(child_turned);
-                },
-                _ => continue,
-            }
+        for child in children.iter() {
+            child.write().parent = Some(node.downgrade());
         }
 
         {
-            node.write().unwrap().children.append(&mut children);
+            node.write().children.append(&mut children);
         }
 
         node
     }
 }
 
-impl XmlStore {
-    pub fn new() -> Arc<RwLock<XmlStore>> {
-        let store = XmlStore {
-            #[allow(clippy::arc_with_non_send_sync)]
-            indices: Arc::new(RwLock::new(HashMap::new())),
-            handle: OnceCell::new(),
+impl From<xmltree::XMLNode> for NodeAsync {
+    fn from(native_node: xmltree::XMLNode) -> Self {
+        let node = match native_node {
+            xmltree::XMLNode::Element(element) => return NodeAsync::from(element),
+            xmltree::XMLNode::Text(content) => XmlNode::new(NodeKind::Text(content), TEXT_CONTENT.into()),
+            xmltree::XMLNode::CData(content) => XmlNode::new(NodeKind::CData(content), TEXT_CONTENT.into()),
+            xmltree::XMLNode::Comment(content) => XmlNode::new(NodeKind::Comment(content), "#comment".into()),
+            xmltree::XMLNode::ProcessingInstruction(target, data) => {
+                let name = target.clone();
+                XmlNode::new(NodeKind::ProcessingInstruction { target, data }, name)
+            }
         };
+        node.into()
+    }
+}
+
+/// Converts `native_nodes` in order, replacing each `include-tree` wrapper
+/// with its own converted children.
+fn convert_nodes(native_nodes: Vec<xmltree::XMLNode>, included_from: Option<&StoreIndex>) -> Vec<NodeAsync> {
+    let mut result: Vec<NodeAsync> = Vec::new();
+
+    for native_node in native_nodes.into_iter() {
+        match native_node {
+            xmltree::XMLNode::Element(element)
+                if element.name == template::INCLUDE_TREE && element.prefix.is_none() =>
+            {
+                let index = element.attributes.get("index").cloned().unwrap_or_default();
+                result.append(&mut convert_nodes(element.children, Some(&index)));
+            }
+            native_node => {
+                let node = NodeAsync::from(native_node);
+                if let Some(index) = included_from {
+                    node.write().included_from = Some(index.clone());
+                }
+                result.push(node);
+            }
+        }
+    }
 
-        #[allow(clippy::arc_with_non_send_sync)]
-        let arc: Arc<RwLock<XmlStore>> = Arc::new(RwLock::new(store));
+    result
+}
 
-        arc.write().unwrap().handle.set(arc.clone()).unwrap();
-        arc.clone()
+/// The root elements of a parsed `source`, and apart from them the comments
+/// and processing instructions around them, each with the number of roots
+/// before it. Text and CDATA outside the roots is dropped.
+type ParsedNodes = (Vec<NodeAsync>, Vec<(usize, NodeAsync)>);
+
+fn parse_nodes(index: &StoreIndex, source: &str, id_strategy: &IdStrategy) -> Result<ParsedNodes, Error> {
+    match xmltree::Element::parse_all(source.as_bytes()) {
+        Ok(nodes_vec) => {
+            let mut nodes: Vec<NodeAsync> = Vec::new();
+            let mut misc: Vec<(usize, NodeAsync)> = Vec::new();
+            for node in convert_nodes(nodes_vec, None).into_iter() {
+                let kind = node.read().kind.clone();
+                match kind {
+                    NodeKind::Element => nodes.push(node),
+                    NodeKind::Comment(_) | NodeKind::ProcessingInstruction { .. } => misc.push((nodes.len(), node)),
+                    NodeKind::Text(_) | NodeKind::CData(_) => {}
+                }
+            }
+            id::assign(id_strategy, &nodes);
+            Ok((nodes, misc))
+        }
+        Err(err) => Err(Error::ParseFailure(ParseFailureContents::new(err, index, source).into())),
     }
+}
 
-    pub fn get_handle(&self) -> Arc<RwLock<XmlStore>> {
-        self.handle.get().unwrap().clone()
+impl XmlStore {
+    pub fn new() -> Arc<RwLock<XmlStore>> {
+        Arc::new_cyclic(|handle| {
+            RwLock::new(XmlStore {
+                indices: Arc::new(RwLock::new(HashMap::new())),
+                duplicate_id_policy: DuplicateIdPolicy::default(),
+                on_duplicate_ids: None,
+                id_strategy: IdStrategy::default(),
+                handle: handle.clone(),
+            })
+        })
+    }
+
+    /// The handle `new` returned, or `None` once every copy of it is dropped,
+    /// which only a clone of the store can see.
+    pub fn get_handle(&self) -> Option<Arc<RwLock<XmlStore>>> {
+        self.handle.upgrade()
     }
 
     pub fn append_from_template(
         &mut self,
         index: StoreIndex,
         template: template::StoreEntryAsync,
-    ) -> Result<StoreEntryAsync, Error> {
-        if self.has(&index) {
-            Err(Error::AlreadyInStore(index))
-        } else {
-            let mut store_guard = self.indices.write().unwrap();
-            let readable_guard = template.read().unwrap();
-
-            match xmltree::Element::parse_all(readable_guard.source.as_bytes()) {
-                Ok(nodes_vec) => {
-                    let nodes_async_vec: Vec<NodeAsync> = nodes_vec
-                        .into_iter()
-                        .filter(|x| matches!(x, xmltree::XMLNode::Element(_)))
-                        .map(|x| NodeAsync::from((*x.as_element().unwrap()).to_owned()))
-                        .collect();
-
-                    #[allow(clippy::arc_with_non_send_sync)]
-                    let store_entry: StoreEntryAsync = Arc::new(RwLock::new(StoreEntry {
-                        store: Arc::downgrade(&self.get_handle()),
-                        nodes: nodes_async_vec[..].into(),
-                        index,
-                        source: readable_guard.source.clone(),
-                    }));
-                    let entry_index: StoreIndex;
-                    {
-                        let entry_guard = store_entry.read().unwrap();
-                        entry_index = entry_guard.index.clone();
-                    }
-                    match store_guard.insert(entry_index.clone(), store_entry.clone()) {
-                        Some(_) => Err(Error::AlreadyInStore(entry_index)),
-                        None => Ok(store_entry),
-                    }
-                }
-                Err(err) => Err(Error::SourceReadFailure(SourceReadFailureContents {
-                    entry_index: index,
-                    failure_message: err.to_string(),
-                })),
-            }
+    ) -> crate::Result<StoreEntryAsync> {
+        let source = template.read().map_err(template::Error::from)?.source.clone();
+        Ok(self.append_parsed(index, source, None)?)
+    }
+
+    /// Renders `template` with `context` and parses the output, unlike
+    /// `append_from_template` which parses the unrendered template source.
+    /// The entry remembers both so it can be rendered again.
+    pub fn append_from_rendered_template(
+        &mut self,
+        index: StoreIndex,
+        template: template::StoreEntryAsync<'static>,
+        context: minijinja::Value,
+    ) -> crate::Result<StoreEntryAsync> {
+        if self.has(&index)? {
+            return Err(Error::AlreadyInStore(index).into());
         }
+
+        let mut origin = RenderOrigin {
+            template,
+            context,
+            version: 0,
+            dependencies: BTreeMap::new(),
+        };
+        let source = origin.render()?;
+        Ok(self.append_parsed(index, source, Some(origin))?)
     }
 
     pub fn append_from_source(
@@ -293,29 +736,61 @@ impl XmlStore {
         index: StoreIndex,
         source: String,
     ) -> Result<StoreEntryAsync, Error> {
-        if self.has(&index) {
+        self.append_parsed(index, source, None)
+    }
+
+    /// Reads and parses `path` without blocking the executor. Takes the
+    /// store's handle rather than a guard so that no lock is held across the
+    /// read.
+    #[cfg(feature = "tokio")]
+    pub async fn append_from_file_async(
+        store: &Arc<RwLock<Self>>,
+        index: StoreIndex,
+        path: &std::path::Path,
+    ) -> Result<StoreEntryAsync, Error> {
+        if store.read()?.has(&index)? {
+            return Err(Error::AlreadyInStore(index));
+        }
+
+        match tokio::fs::read_to_string(path).await {
+            Ok(source) => store.write()?.append_parsed(index, source, None),
+            Err(err) => Err(Error::SourceReadFailure(SourceReadFailureContents::new(Some(index), path, err).into())),
+        }
+    }
+
+    fn append_parsed(
+        &mut self,
+        index: StoreIndex,
+        source: String,
+        origin: Option<RenderOrigin>,
+    ) -> Result<StoreEntryAsync, Error> {
+        if self.has(&index)? {
             Err(Error::AlreadyInStore(index))
         } else {
-            let mut store_guard = self.indices.write().unwrap();
-
-            match xmltree::Element::parse_all(source.as_bytes()) {
-                Ok(nodes_vec) => {
-                    let nodes_async_vec: Vec<NodeAsync> = nodes_vec
-                        .into_iter()
-                        .filter(|x| matches!(x, xmltree::XMLNode::Element(_)))
-                        .map(|x| NodeAsync::from((*x.as_element().unwrap()).to_owned()))
-                        .collect();
-
-                    #[allow(clippy::arc_with_non_send_sync)]
-                    let store_entry: StoreEntryAsync = Arc::new(RwLock::new(StoreEntry {
-                        store: Arc::downgrade(&self.get_handle()),
+            let mut store_guard = self.indices.write()?;
+
+            match parse_nodes(&index, &source, &self.id_strategy) {
+                Ok((nodes_async_vec, misc)) => {
+                    let mut entry = StoreEntry {
+                        store: self.handle.clone(),
                         nodes: nodes_async_vec[..].into(),
+                        misc: misc[..].into(),
                         index,
-                        source: source.clone(),
-                    }));
+                        source,
+                        origin,
+                        class_index: Arc::new(RwLock::new(AttributeIndex::classes(&[]))),
+                        id_index: Arc::new(RwLock::new(AttributeIndex::ids(&[]))),
+                        subscriptions: Vec::new(),
+                        duplicate_id_policy: self.duplicate_id_policy,
+                        on_duplicate_ids: self.on_duplicate_ids.clone(),
+                        id_strategy: self.id_strategy.clone(),
+                    };
+                    entry.index_nodes();
+                    entry.check_duplicate_ids()?;
+                    let store_entry: StoreEntryAsync = Arc::new(RwLock::new(entry));
                     let entry_index: StoreIndex;
                     {
-                        let entry_guard = store_entry.read().unwrap();
+                        let entry_guard = store_entry.read()?;
                         entry_index = entry_guard.index.clone();
                     }
                     match store_guard.insert(entry_index.clone(), store_entry.clone()) {
@@ -323,26 +798,67 @@ impl XmlStore {
                         None => Ok(store_entry),
                     }
                 }
-                Err(err) => Err(Error::SourceReadFailure(SourceReadFailureContents {
-                    entry_index: index,
-                    failure_message: err.to_string(),
-                })),
+                Err(err) => Err(err),
             }
         }
     }
 
-    pub fn has(&self, index: &StoreIndex) -> bool {
-        let indices_guard = self.indices.read().unwrap();
-        indices_guard.contains_key(index)
+    /// Renders again every entry that `depends_on` template `template`, with
+    /// the context it was last rendered with. Entries that did not come from
+    /// a template are left alone. Each entry is rerendered through
+    /// `StoreEntry::rerender`, so its observers run with no entry locked.
+    pub fn rerender_dependents(&self, template: &StoreIndex) -> Vec<(StoreIndex, crate::Result<Vec<Patch>>)> {
+        let entries: Vec<(StoreIndex, StoreEntryAsync)> = match self.indices.read() {
+            Ok(indices_guard) => indices_guard.iter().map(|(x, y)| (x.clone(), y.clone())).collect(),
+            Err(err) => return vec![(template.clone(), Err(Error::from(err).into()))],
+        };
+
+        let mut result: Vec<(StoreIndex, crate::Result<Vec<Patch>>)> = Vec::new();
+        for (index, entry) in entries.into_iter() {
+            let context = {
+                let entry_guard = match entry.read() {
+                    Ok(entry_guard) => entry_guard,
+                    Err(err) => {
+                        result.push((index, Err(Error::from(err).into())));
+                        continue;
+                    }
+                };
+                match entry_guard.depends_on(template) {
+                    Ok(true) => {}
+                    Ok(false) => continue,
+                    Err(err) => {
+                        result.push((index, Err(err)));
+                        continue;
+                    }
+                }
+                match &entry_guard.origin {
+                    Some(origin) => origin.context.clone(),
+                    None => continue,
+                }
+            };
+            result.push((index, StoreEntry::rerender(&entry, context)));
+        }
+        result.sort_by(|a, b| a.0.cmp(&b.0));
+        result
     }
 
-    pub fn get(&self, index: &StoreIndex) -> Option<StoreEntryAsync> {
-        let indices_guard = self.indices.read().unwrap();
-        indices_guard.get(index).cloned()
+    pub fn has(&self, index: &StoreIndex) -> Result<bool, Error> {
+        Ok(self.indices.read()?.contains_key(index))
     }
 
-    pub fn remove(&mut self, index: &StoreIndex) {
-        let mut indices_guard = self.indices.write().unwrap();
-        indices_guard.remove(index);
+    pub fn get(&self, index: &StoreIndex) -> Result<StoreEntryAsync, Error> {
+        let indices_guard = self.indices.read()?;
+        match indices_guard.get(index) {
+            Some(entry) => Ok(entry.clone()),
+            None => Err(Error::MissingIndex(index.clone())),
+        }
+    }
+
+    pub fn remove(&mut self, index: &StoreIndex) -> Result<(), Error> {
+        let mut indices_guard = self.indices.write()?;
+        match indices_guard.remove(index) {
+            Some(_) => Ok(()),
+            None => Err(Error::MissingIndex(index.clone())),
+        }
     }
 }
@@ -0,0 +1,295 @@
+use std::vec::Vec;
+
+use super::id::random_id;
+use super::observe::{self, NodeEvent, Observers};
+use super::{Error, NodeAsync, XmlNode};
+
+impl NodeAsync {
+    pub fn parent(&self) -> Option<NodeAsync> {
+        let node_guard = self.read();
+        node_guard.parent.as_ref().and_then(|x| x.upgrade()).map(NodeAsync::from)
+    }
+
+    pub fn index_of(&self, child: &NodeAsync) -> Option<usize> {
+        let node_guard = self.read();
+        node_guard.children.iter().position(|x| x.ptr_eq(child))
+    }
+
+    /// Whether `self` is `other` or one of its ancestors.
+    pub fn contains(&self, other: &NodeAsync) -> bool {
+        let mut current = Some(other.clone());
+        while let Some(node) = current {
+            if node.ptr_eq(self) {
+                return true;
+            }
+            current = node.parent();
+        }
+        false
+    }
+
+    /// Moves `child` to the end of this node's children, detaching it from
+    /// wherever it was first.
+    pub fn append_child(&self, child: &NodeAsync) -> Result<(), Error> {
+        self.insert_at(child, None)
+    }
+
+    /// Moves `child` in front of `reference`, which must be a child of this
+    /// node, detaching it from wherever it was first.
+    pub fn insert_before(&self, child: &NodeAsync, reference: &NodeAsync) -> Result<(), Error> {
+        if child.ptr_eq(reference) {
+            return match self.index_of(reference) {
+                Some(_) => Ok(()),
+                None => Err(Error::NotAChild(reference.to_string())),
+            };
+        }
+        self.insert_at(child, Some(reference))
+    }
+
+    /// Takes `child` out of this node's children.
+    pub fn remove(&self, child: &NodeAsync) -> Result<(), Error> {
+        if self.index_of(child).is_none() {
+            return Err(Error::NotAChild(child.to_string()));
+        }
+
+        let mut events: Vec<NodeEvent> = Vec::new();
+        child.detach_into(&mut events);
+        observe::dispatch(events);
+        Ok(())
+    }
+
+    /// Takes this node out of its parent's children. Returns `false` if it
+    /// had no parent.
+    pub fn detach(&self) -> bool {
+        let mut events: Vec<NodeEvent> = Vec::new();
+        let detached = self.detach_into(&mut events);
+        observe::dispatch(events);
+        detached
+    }
+
+    /// Puts `replacement` where this node is in its parent's children and
+    /// detaches this node.
+    pub fn replace_with(&self, replacement: &NodeAsync) -> Result<(), Error> {
+        if self.ptr_eq(replacement) {
+            return Ok(());
+        }
+
+        let parent = match self.parent() {
+            Some(parent) => parent,
+            None => return Err(Error::Detached(self.to_string())),
+        };
+        if replacement.contains(&parent) {
+            return Err(Error::CyclicInsertion(replacement.to_string()));
+        }
+
+        let mut events: Vec<NodeEvent> = Vec::new();
+        replacement.detach_into(&mut events);
+
+        let index = parent.index_of(self).unwrap();
+        self.detach_into(&mut events);
+        parent.attach_into(replacement, index, &mut events);
+
+        observe::dispatch(events);
+        Ok(())
+    }
+
+    /// Deep copy of this node with no parent and no observers. Every id,
+    /// user-supplied or not, is replaced with a fresh random one whatever the
+    /// store's `IdStrategy`, so the copy never collides with the original.
+    pub fn clone_subtree(&self) -> NodeAsync {
+        let node_guard = self.read();
+
+        let mut attributes = node_guard.attributes.clone();
+        attributes.insert(("Default".into(), "id".into()), random_id());
+
+        let copy: NodeAsync = XmlNode {
+            kind: node_guard.kind.clone(),
+
+            prefix: node_guard.prefix.clone(),
+            namespace: node_guard.namespace.clone(),
+            namespaces: node_guard.namespaces.clone(),
+
+            name: node_guard.name.clone(),
+            attributes,
+
+            children: Vec::default(),
+            parent: None,
+            included_from: node_guard.included_from.clone(),
+
+            observers: Observers::default(),
+        }
+        .into();
+
+        let children: Vec<NodeAsync> = node_guard.children.iter().map(|x| x.clone_subtree()).collect();
+        for child in children.iter() {
+            child.write().parent = Some(copy.downgrade());
+        }
+        copy.write().children = children;
+
+        copy
+    }
+
+    fn insert_at(&self, child: &NodeAsync, reference: Option<&NodeAsync>) -> Result<(), Error> {
+        if !self.read().is_element() {
+            return Err(Error::NotAnElement(self.to_string()));
+        }
+        if child.contains(self) {
+            return Err(Error::CyclicInsertion(child.to_string()));
+        }
+        if let Some(reference) = reference {
+            if self.index_of(reference).is_none() {
+                return Err(Error::NotAChild(reference.to_string()));
+            }
+        }
+
+        let mut events: Vec<NodeEvent> = Vec::new();
+        child.detach_into(&mut events);
+
+        // look the reference up again, detaching may have shifted it
+        let index = match reference {
+            Some(reference) => self.index_of(reference).unwrap(),
+            None => self.read().children.len(),
+        };
+        self.attach_into(child, index, &mut events);
+
+        observe::dispatch(events);
+        Ok(())
+    }
+
+    fn attach_into(&self, child: &NodeAsync, index: usize, events: &mut Vec<NodeEvent>) {
+        child.write().parent = Some(self.downgrade());
+        self.write().children.insert(index, child.clone());
+
+        events.push(NodeEvent::ChildInserted {
+            parent: self.clone(),
+            child: child.clone(),
+            index,
+        });
+    }
+
+    fn detach_into(&self, events: &mut Vec<NodeEvent>) -> bool {
+        let parent = match self.parent() {
+            Some(parent) => parent,
+            None => return false,
+        };
+
+        let index = match parent.index_of(self) {
+            Some(index) => index,
+            None => return false,
+        };
+        parent.write().children.remove(index);
+        self.write().parent = None;
+
+        events.push(NodeEvent::ChildRemoved {
+            parent,
+            child: self.clone(),
+            index,
+        });
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::xml::{parse_nodes, Error, IdStrategy, NodeAsync, XmlStore};
+
+    fn nodes(source: &str) -> Vec<NodeAsync> {
+        parse_nodes(&"test".into(), source, &IdStrategy::default()).unwrap().0
+    }
+
+    fn names(node: &NodeAsync) -> Vec<String> {
+        node.read().children.iter().map(|x| x.read().name.clone()).collect()
+    }
+
+    fn child(node: &NodeAsync, index: usize) -> NodeAsync {
+        node.read().children[index].clone()
+    }
+
+    #[test]
+    fn inserting_an_ancestor_or_itself_is_refused() {
+        let root = nodes("<A><B><C/></B></A>").remove(0);
+        let b = child(&root, 0);
+        let c = child(&b, 0);
+
+        assert!(matches!(c.append_child(&root), Err(Error::CyclicInsertion(_))));
+        assert!(matches!(b.append_child(&b), Err(Error::CyclicInsertion(_))));
+        assert!(matches!(c.replace_with(&b), Err(Error::CyclicInsertion(_))));
+        assert_eq!(names(&root), ["B"]);
+        assert_eq!(names(&b), ["C"]);
+        assert!(c.parent().unwrap().ptr_eq(&b));
+    }
+
+    #[test]
+    fn insert_before_a_later_sibling_accounts_for_the_detach() {
+        let root = nodes("<Row><A/><B/><C/><D/></Row>").remove(0);
+        let (a, c) = (child(&root, 0), child(&root, 2));
+
+        root.insert_before(&a, &c).unwrap();
+        assert_eq!(names(&root), ["B", "A", "C", "D"]);
+        root.insert_before(&c, &c).unwrap();
+        assert_eq!(names(&root), ["B", "A", "C", "D"]);
+
+        let outsider = nodes("<E/>").remove(0);
+        assert!(matches!(root.insert_before(&outsider, &outsider), Err(Error::NotAChild(_))));
+        assert!(matches!(root.insert_before(&c, &outsider), Err(Error::NotAChild(_))));
+        assert!(outsider.parent().is_none());
+    }
+
+    #[test]
+    fn replace_with_takes_the_replaced_node_place() {
+        let root = nodes("<Row><A/><B/></Row>").remove(0);
+        let other = nodes("<Other><C/></Other>").remove(0);
+        let (a, c) = (child(&root, 0), child(&other, 0));
+
+        a.replace_with(&c).unwrap();
+        assert_eq!(names(&root), ["C", "B"]);
+        assert!(names(&other).is_empty());
+        assert!(c.parent().unwrap().ptr_eq(&root));
+        assert!(a.parent().is_none());
+        assert!(matches!(a.replace_with(&c), Err(Error::Detached(_))));
+    }
+
+    #[test]
+    fn detach_and_remove_clear_the_parent_link() {
+        let root = nodes("<Row><A/><B/></Row>").remove(0);
+        let (a, b) = (child(&root, 0), child(&root, 1));
+
+        assert!(a.detach());
+        assert!(!a.detach());
+        assert!(a.parent().is_none());
+        assert!(matches!(root.remove(&a), Err(Error::NotAChild(_))));
+
+        root.remove(&b).unwrap();
+        assert!(b.parent().is_none());
+        assert!(names(&root).is_empty());
+    }
+
+    #[test]
+    fn only_elements_take_children() {
+        let root = nodes("<Row>text<!-- note --></Row>").remove(0);
+        let (text, comment) = (child(&root, 0), child(&root, 1));
+        let orphan = nodes("<A/>").remove(0);
+
+        assert!(matches!(text.append_child(&orphan), Err(Error::NotAnElement(_))));
+        assert!(matches!(comment.append_child(&orphan), Err(Error::NotAnElement(_))));
+        assert!(orphan.parent().is_none());
+        root.append_child(&orphan).unwrap();
+        assert_eq!(names(&root), ["text-content", "#comment", "A"]);
+    }
+
+    #[test]
+    fn cloned_subtree_gets_fresh_ids() {
+        let store = XmlStore::new();
+        let source = r#"<Container id="A"><Row id="B"><Column id="C"/></Row></Container>"#;
+        let entry = store.write().unwrap().append_from_source("test".into(), source.into()).unwrap();
+        let entry_guard = entry.read().unwrap();
+
+        let original = entry_guard.get_element_by_id("A").unwrap();
+        let copy = original.clone_subtree();
+        original.append_child(&copy).unwrap();
+
+        assert!(entry_guard.duplicate_ids().is_empty());
+        assert_eq!(entry_guard.get_element_by_id("B").unwrap().read().children.len(), 1);
+        assert!(copy.parent().unwrap().ptr_eq(&original));
+        assert_eq!(copy.read().children[0].read().children[0].read().name, "Column");
+    }
+}
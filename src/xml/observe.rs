@@ -0,0 +1,226 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::vec::Vec;
+
+use super::{Namespace, NodeAsync, NodeKind};
+
+/// What happened to a node. Events are delivered after the change is made
+/// and every lock taken for it is released, so callbacks may read the tree.
+#[derive(Debug, Clone)]
+pub enum NodeEvent {
+    /// `value` is `None` when the attribute was removed.
+    AttributeChanged {
+        node: NodeAsync,
+        key: (Namespace, String),
+        value: Option<String>,
+    },
+    ChildInserted {
+        parent: NodeAsync,
+        child: NodeAsync,
+        index: usize,
+    },
+    ChildRemoved {
+        parent: NodeAsync,
+        child: NodeAsync,
+        index: usize,
+    },
+    /// New contents of a text, CDATA, comment or processing instruction node.
+    TextChanged { node: NodeAsync, kind: NodeKind },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// Only changes to the node itself and its direct child list.
+    Node,
+    /// Changes anywhere at or below the node.
+    Subtree,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SubscriptionId(u64);
+
+type Callback = Arc<dyn Fn(&NodeEvent) + Send + Sync>;
+
+#[derive(Clone, Default)]
+pub struct Observers(Vec<(SubscriptionId, Scope, Callback)>);
+
+impl std::fmt::Debug for Observers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Observers({})", self.0.len())
+    }
+}
+
+impl Observers {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl NodeEvent {
+    /// The node whose own observers hear about this event first.
+    pub fn target(&self) -> &NodeAsync {
+        match self {
+            Self::AttributeChanged { node, .. } | Self::TextChanged { node, .. } => node,
+            Self::ChildInserted { parent, .. } | Self::ChildRemoved { parent, .. } => parent,
+        }
+    }
+}
+
+impl NodeAsync {
+    pub fn subscribe(&self, scope: Scope, callback: impl Fn(&NodeEvent) + Send + Sync + 'static) -> SubscriptionId {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+        let id = SubscriptionId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+        self.write().observers.0.push((id, scope, Arc::new(callback)));
+        id
+    }
+
+    pub fn unsubscribe(&self, id: SubscriptionId) -> bool {
+        let mut node_guard = self.write();
+        let before = node_guard.observers.0.len();
+        node_guard.observers.0.retain(|(x, _, _)| *x != id);
+        node_guard.observers.0.len() != before
+    }
+}
+
+/// Delivers each event to the target's observers, then to `Subtree`
+/// observers on every ancestor.
+pub(super) fn dispatch(events: Vec<NodeEvent>) {
+    for event in events.iter() {
+        let mut callbacks: Vec<Callback> = Vec::new();
+        let mut current = Some(event.target().clone());
+        let mut at_target = true;
+
+        while let Some(node) = current {
+            let node_guard = node.read();
+            callbacks.extend(
+                node_guard
+                    .observers
+                    .0
+                    .iter()
+                    .filter(|(_, scope, _)| at_target || *scope == Scope::Subtree)
+                    .map(|(_, _, callback)| callback.clone()),
+            );
+            current = node_guard.parent.as_ref().and_then(|x| x.upgrade()).map(NodeAsync::from);
+            at_target = false;
+        }
+
+        for callback in callbacks {
+            callback(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::xml::{parse_nodes, IdStrategy};
+
+    fn nodes(source: &str) -> Vec<NodeAsync> {
+        parse_nodes(&"test".into(), source, &IdStrategy::default()).unwrap().0
+    }
+
+    fn describe(event: &NodeEvent) -> String {
+        match event {
+            NodeEvent::AttributeChanged { node, key, value } => {
+                format!("{} {}={value:?}", node.read().name, key.1)
+            }
+            NodeEvent::ChildInserted { parent, child, index } => {
+                format!("insert {} into {} at {index}", child.read().name, parent.read().name)
+            }
+            NodeEvent::ChildRemoved { parent, child, index } => {
+                format!("remove {} from {} at {index}", child.read().name, parent.read().name)
+            }
+            NodeEvent::TextChanged { kind, .. } => format!("text {kind:?}"),
+        }
+    }
+
+    /// Subscribes to `node` and returns what it hears, described.
+    fn record(node: &NodeAsync, scope: Scope) -> (SubscriptionId, Arc<Mutex<Vec<String>>>) {
+        let heard: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::new()));
+        let sink = heard.clone();
+        let id = node.subscribe(scope, move |event| sink.lock().unwrap().push(describe(event)));
+        (id, heard)
+    }
+
+    #[test]
+    fn node_scope_hears_only_its_own_changes() {
+        let root = nodes("<Page><Row><Column/></Row></Page>").remove(0);
+        let row = root.read().children[0].clone();
+        let column = row.read().children[0].clone();
+
+        let (_, on_page) = record(&root, Scope::Node);
+        let (_, on_row) = record(&row, Scope::Node);
+        let (_, below_page) = record(&root, Scope::Subtree);
+
+        column.set_attribute("Default", "span", "2".into());
+        row.set_attribute("Default", "kind", "wide".into());
+        column.detach();
+
+        assert!(on_page.lock().unwrap().is_empty());
+        assert_eq!(*on_row.lock().unwrap(), ["Row kind=Some(\"wide\")", "remove Column from Row at 0"]);
+        assert_eq!(
+            *below_page.lock().unwrap(),
+            ["Column span=Some(\"2\")", "Row kind=Some(\"wide\")", "remove Column from Row at 0"]
+        );
+    }
+
+    #[test]
+    fn unsubscribed_callbacks_are_not_called() {
+        let root = nodes("<Page/>").remove(0);
+        let (id, heard) = record(&root, Scope::Subtree);
+        let (_, kept) = record(&root, Scope::Node);
+
+        assert!(root.unsubscribe(id));
+        assert!(!root.unsubscribe(id));
+        root.set_attribute("Default", "kind", "wide".into());
+
+        assert!(heard.lock().unwrap().is_empty());
+        assert_eq!(kept.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn mutations_and_class_changes_fire_events() {
+        let root = nodes(r#"<Page><Row class="a"/><Row/>text</Page>"#).remove(0);
+        let (first, second, text) = {
+            let children = root.read().children.clone();
+            (children[0].clone(), children[1].clone(), children[2].clone())
+        };
+        let (_, heard) = record(&root, Scope::Subtree);
+
+        first.add_class("b");
+        first.add_class("b");
+        first.remove_class("a");
+        first.toggle_class("b");
+        root.insert_before(&second, &first).unwrap();
+        text.set_text("changed".into());
+        first.remove_attribute("Default", "missing");
+
+        assert_eq!(
+            *heard.lock().unwrap(),
+            [
+                "Row class=Some(\"a b\")",
+                "Row class=Some(\"b\")",
+                "Row class=None",
+                "remove Row from Page at 1",
+                "insert Row into Page at 0",
+                "text Text(\"changed\")",
+            ]
+        );
+    }
+
+    #[test]
+    fn unchanged_values_fire_nothing() {
+        let root = nodes(r#"<Page kind="wide">text</Page>"#).remove(0);
+        let text = root.read().children[0].clone();
+        let (_, heard) = record(&root, Scope::Subtree);
+
+        root.set_attribute("Default", "kind", "wide".into());
+        text.set_text("text".into());
+        root.remove_class("missing");
+
+        assert!(heard.lock().unwrap().is_empty());
+    }
+}
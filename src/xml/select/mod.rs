@@ -0,0 +1,659 @@
+mod parse;
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::vec::Vec;
+
+use super::{Error, Namespace, NodeAsync, NodeKind, XmlNode, TEXT_CONTENT};
+pub(super) use parse::write_identifier;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Combinator {
+    /// `A B`: B is anywhere below A
+    Descendant,
+    /// `A > B`: B is a direct child of A
+    Child,
+    /// `A + B`: B immediately follows A under the same parent
+    NextSibling,
+    /// `A ~ B`: B follows A somewhere under the same parent
+    SubsequentSibling,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AttributeOperator {
+    /// `[attr]`
+    Exists,
+    /// `[attr=value]`
+    Equals(String),
+    /// `[attr~=value]`: whitespace-separated list contains value
+    Includes(String),
+    /// `[attr|=value]`: equals value or starts with `value-`
+    DashMatch(String),
+    /// `[attr^=value]`
+    Prefix(String),
+    /// `[attr$=value]`
+    Suffix(String),
+    /// `[attr*=value]`
+    Substring(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AttributePredicate {
+    pub namespace: Namespace,
+    pub name: String,
+    pub operator: AttributeOperator,
+}
+
+/// The `an+b` argument of `:nth-child`, counted from 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NthIndex {
+    pub step: i32,
+    pub offset: i32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PseudoClass {
+    FirstChild,
+    NthChild(NthIndex),
+    Not(Box<CompoundSelector>),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompoundSelector {
+    pub name: Option<String>,
+    pub namespace: Option<Namespace>,
+    pub id: Option<String>,
+    pub classes: Vec<String>,
+    pub attributes: Vec<AttributePredicate>,
+    pub pseudo_classes: Vec<PseudoClass>,
+}
+
+/// The parts of a node that a compound selector looks at.
+#[derive(Debug, Clone, Copy)]
+pub struct NodeView<'a> {
+    pub kind: &'a NodeKind,
+    pub name: &'a str,
+    pub namespace: Option<&'a Namespace>,
+    pub attributes: &'a HashMap<(Namespace, String), String>,
+}
+
+/// A tree that selectors can walk, so the same selector runs over `NodeAsync`
+/// handles and over other node representations.
+pub trait SelectorTree {
+    type Node: Clone;
+
+    fn view<R>(&self, node: &Self::Node, f: impl FnOnce(NodeView<'_>) -> R) -> R;
+    fn parent(&self, node: &Self::Node) -> Option<Self::Node>;
+    /// Element siblings before `node`, nearest last. Text, comments and the
+    /// like are skipped so that `A + B` behaves as in CSS.
+    fn preceding_elements(&self, node: &Self::Node) -> Vec<Self::Node>;
+}
+
+/// `NodeAsync` trees, walked through each node's own lock and parent link.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AsyncTree;
+
+/// A chain of compound selectors joined by combinators, read left to right
+/// the same way CSS is: `compounds[0] combinators[0] compounds[1] ...`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeSelector {
+    compounds: Vec<CompoundSelector>,
+    combinators: Vec<Combinator>,
+}
+
+impl AttributeOperator {
+    fn matches(&self, value: &str) -> bool {
+        match self {
+            Self::Exists => true,
+            Self::Equals(expected) => value == expected,
+            Self::Includes(expected) => value.split_whitespace().any(|x| x == expected),
+            Self::DashMatch(expected) => {
+                value == expected
+                    || (value.starts_with(expected.as_str()) && value[expected.len()..].starts_with('-'))
+            }
+            Self::Prefix(expected) => !expected.is_empty() && value.starts_with(expected.as_str()),
+            Self::Suffix(expected) => !expected.is_empty() && value.ends_with(expected.as_str()),
+            Self::Substring(expected) => !expected.is_empty() && value.contains(expected.as_str()),
+        }
+    }
+}
+
+impl<'a> NodeView<'a> {
+    pub fn is_element(&self) -> bool {
+        *self.kind == NodeKind::Element
+    }
+
+    pub fn is_text(&self) -> bool {
+        matches!(self.kind, NodeKind::Text(_) | NodeKind::CData(_))
+    }
+
+    pub fn get_attribute(&self, namespace: &str, attribute: &str) -> Option<&'a str> {
+        let key = &(namespace.into(), attribute.into());
+        self.attributes.get(key).map(String::as_str)
+    }
+}
+
+impl<'a> From<&'a XmlNode> for NodeView<'a> {
+    fn from(node: &'a XmlNode) -> Self {
+        Self {
+            kind: &node.kind,
+            name: &node.name,
+            namespace: node.namespace.as_ref(),
+            attributes: &node.attributes,
+        }
+    }
+}
+
+impl SelectorTree for AsyncTree {
+    type Node = NodeAsync;
+
+    fn view<R>(&self, node: &NodeAsync, f: impl FnOnce(NodeView<'_>) -> R) -> R {
+        f(NodeView::from(&*node.read()))
+    }
+
+    fn parent(&self, node: &NodeAsync) -> Option<NodeAsync> {
+        node.parent()
+    }
+
+    fn preceding_elements(&self, node: &NodeAsync) -> Vec<NodeAsync> {
+        let parent = match node.parent() {
+            Some(parent) => parent,
+            None => return vec![],
+        };
+
+        let parent_guard = parent.read();
+        parent_guard
+            .children
+            .iter()
+            .take_while(|x| !x.ptr_eq(node))
+            .filter(|x| x.read().is_element())
+            .cloned()
+            .collect()
+    }
+}
+
+impl AttributePredicate {
+    fn matches(&self, node: &NodeView) -> bool {
+        match node.get_attribute(&self.namespace, &self.name) {
+            Some(value) => self.operator.matches(value),
+            None => false,
+        }
+    }
+}
+
+impl NthIndex {
+    pub fn matches(&self, position: usize) -> bool {
+        let position = position as i64;
+        let (step, offset) = (self.step as i64, self.offset as i64);
+        if step == 0 {
+            return position == offset;
+        }
+        let distance = position - offset;
+        distance % step == 0 && distance / step >= 0
+    }
+}
+
+impl PseudoClass {
+    fn matches_in<T: SelectorTree>(&self, tree: &T, node: &T::Node) -> bool {
+        // nodes without a parent are the only child of their document
+        let position = || tree.preceding_elements(node).len() + 1;
+        match self {
+            Self::FirstChild => position() == 1,
+            Self::NthChild(index) => index.matches(position()),
+            Self::Not(inner) => !inner.matches_in(tree, node),
+        }
+    }
+}
+
+impl CompoundSelector {
+    pub fn is_universal(&self) -> bool {
+        self.name.is_none()
+            && self.namespace.is_none()
+            && self.id.is_none()
+            && self.classes.is_empty()
+            && self.attributes.is_empty()
+            && self.pseudo_classes.is_empty()
+    }
+
+    pub fn matches(&self, node: &NodeAsync) -> bool {
+        self.matches_in(&AsyncTree, node)
+    }
+
+    pub fn matches_in<T: SelectorTree>(&self, tree: &T, node: &T::Node) -> bool {
+        tree.view(node, |x| self.matches_view(&x)) && self.pseudo_classes.iter().all(|x| x.matches_in(tree, node))
+    }
+
+    fn matches_view(&self, node: &NodeView) -> bool {
+        // only elements are selectable, except that an explicit `text-content`
+        // type selector still reaches text the way it did when text was parsed
+        // into synthesized elements
+        let legacy_text = node.is_text() && self.name.as_deref() == Some(TEXT_CONTENT);
+        if !node.is_element() && !legacy_text {
+            return false;
+        }
+
+        if let Some(name) = &self.name {
+            if node.name != name {
+                return false;
+            }
+        }
+
+        if let Some(namespace) = &self.namespace {
+            if node.namespace != Some(namespace) {
+                return false;
+            }
+        }
+
+        if let Some(id) = &self.id {
+            if node.get_attribute("Default", "id") != Some(id.as_str()) {
+                return false;
+            }
+        }
+
+        if !self.classes.is_empty() {
+            let class_attribute = node.get_attribute("Default", "class").unwrap_or_default();
+            let classes: Vec<&str> = class_attribute.split_whitespace().collect();
+            if !self.classes.iter().all(|x| classes.contains(&x.as_str())) {
+                return false;
+            }
+        }
+
+        self.attributes.iter().all(|x| x.matches(node))
+    }
+}
+
+impl NodeSelector {
+    pub fn new() -> Self {
+        Self {
+            compounds: vec![CompoundSelector::default()],
+            combinators: Vec::new(),
+        }
+    }
+
+    pub fn parse(selector: &str) -> Result<Self, Error> {
+        parse::parse(selector)
+    }
+
+    /// Builds a selector from the `Parent > Child` path that `NodeAsync`
+    /// displays, which matches `node` and every node sharing its path.
+    /// Comments and processing instructions are never matched, so they have
+    /// no selector.
+    pub fn from_node(node: &NodeAsync) -> Result<Self, Error> {
+        if matches!(node.read().kind, NodeKind::Comment(_) | NodeKind::ProcessingInstruction { .. }) {
+            return Err(Error::Unselectable(node.to_string()));
+        }
+        Self::parse(&node.to_string())
+    }
+
+    pub fn compounds(&self) -> &[CompoundSelector] {
+        &self.compounds
+    }
+
+    pub fn combinators(&self) -> &[Combinator] {
+        &self.combinators
+    }
+
+    fn current(&mut self) -> &mut CompoundSelector {
+        self.compounds.last_mut().unwrap()
+    }
+
+    pub fn named(mut self, name: String) -> Self {
+        self.current().name = Some(name);
+        self
+    }
+
+    pub fn namespaced(mut self, namespace: Namespace) -> Self {
+        self.current().namespace = Some(namespace);
+        self
+    }
+
+    pub fn with_id(mut self, id: String) -> Self {
+        self.current().id = Some(id);
+        self
+    }
+
+    pub fn with_class(mut self, class: String) -> Self {
+        self.current().classes.push(class);
+        self
+    }
+
+    pub fn with_attribute(mut self, namespace: Namespace, name: String, operator: AttributeOperator) -> Self {
+        self.current().attributes.push(AttributePredicate {
+            namespace,
+            name,
+            operator,
+        });
+        self
+    }
+
+    pub fn first_child(mut self) -> Self {
+        self.current().pseudo_classes.push(PseudoClass::FirstChild);
+        self
+    }
+
+    pub fn nth_child(mut self, step: i32, offset: i32) -> Self {
+        self.current().pseudo_classes.push(PseudoClass::NthChild(NthIndex { step, offset }));
+        self
+    }
+
+    pub fn not(mut self, inner: CompoundSelector) -> Self {
+        self.current().pseudo_classes.push(PseudoClass::Not(Box::new(inner)));
+        self
+    }
+
+    pub fn combined(mut self, combinator: Combinator) -> Self {
+        self.combinators.push(combinator);
+        self.compounds.push(CompoundSelector::default());
+        self
+    }
+
+    pub fn descendant(self) -> Self {
+        self.combined(Combinator::Descendant)
+    }
+
+    pub fn child(self) -> Self {
+        self.combined(Combinator::Child)
+    }
+
+    pub fn next_sibling(self) -> Self {
+        self.combined(Combinator::NextSibling)
+    }
+
+    pub fn subsequent_sibling(self) -> Self {
+        self.combined(Combinator::SubsequentSibling)
+    }
+
+    pub fn lock(self) -> Arc<RwLock<Self>> {
+        Arc::new(RwLock::new(self))
+    }
+
+    pub fn matches(&self, node: &NodeAsync) -> bool {
+        self.matches_in(&AsyncTree, node)
+    }
+
+    pub fn matches_in<T: SelectorTree>(&self, tree: &T, node: &T::Node) -> bool {
+        self.matches_at(tree, node, self.compounds.len() - 1)
+    }
+
+    /// Collects `root` and every node below it that matches, in document order.
+    /// Combinators may look past `root` at its ancestors and siblings.
+    pub fn apply(&self, root: NodeAsync) -> Arc<[NodeAsync]> {
+        let mut stack: Vec<NodeAsync> = vec![root];
+        let mut matched: Vec<NodeAsync> = vec![];
+
+        while let Some(node) = stack.pop() {
+            if self.matches(&node) {
+                matched.push(node.clone());
+            }
+
+            let node_guard = node.read();
+            for child in node_guard.children.iter().rev() {
+                stack.push(child.clone());
+            }
+        }
+
+        matched[..].into()
+    }
+
+    fn matches_at<T: SelectorTree>(&self, tree: &T, node: &T::Node, position: usize) -> bool {
+        if !self.compounds[position].matches_in(tree, node) {
+            return false;
+        }
+        if position == 0 {
+            return true;
+        }
+
+        let next = position - 1;
+        match self.combinators[next] {
+            Combinator::Child => match tree.parent(node) {
+                Some(parent) => self.matches_at(tree, &parent, next),
+                None => false,
+            },
+            Combinator::Descendant => {
+                let mut ancestor = tree.parent(node);
+                while let Some(current) = ancestor {
+                    if self.matches_at(tree, &current, next) {
+                        return true;
+                    }
+                    ancestor = tree.parent(&current);
+                }
+                false
+            }
+            Combinator::NextSibling => match tree.preceding_elements(node).last() {
+                Some(sibling) => self.matches_at(tree, sibling, next),
+                None => false,
+            },
+            Combinator::SubsequentSibling => tree
+                .preceding_elements(node)
+                .iter()
+                .any(|x| self.matches_at(tree, x, next)),
+        }
+    }
+}
+
+impl Default for NodeSelector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::str::FromStr for NodeSelector {
+    type Err = Error;
+
+    fn from_str(selector: &str) -> Result<Self, Self::Err> {
+        Self::parse(selector)
+    }
+}
+
+impl std::fmt::Display for AttributePredicate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[")?;
+        if self.namespace != "Default" {
+            parse::write_identifier(f, &self.namespace)?;
+            write!(f, "|")?;
+        }
+        parse::write_identifier(f, &self.name)?;
+
+        let (operator, value) = match &self.operator {
+            AttributeOperator::Exists => return write!(f, "]"),
+            AttributeOperator::Equals(value) => ("=", value),
+            AttributeOperator::Includes(value) => ("~=", value),
+            AttributeOperator::DashMatch(value) => ("|=", value),
+            AttributeOperator::Prefix(value) => ("^=", value),
+            AttributeOperator::Suffix(value) => ("$=", value),
+            AttributeOperator::Substring(value) => ("*=", value),
+        };
+        write!(f, "{}\"{}\"]", operator, value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+impl std::fmt::Display for CompoundSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(namespace) = &self.namespace {
+            parse::write_identifier(f, namespace)?;
+            write!(f, "|")?;
+        }
+        match &self.name {
+            Some(name) => parse::write_identifier(f, name)?,
+            None if self.is_universal() || self.namespace.is_some() => write!(f, "*")?,
+            None => {}
+        }
+        if let Some(id) = &self.id {
+            write!(f, "#")?;
+            parse::write_identifier(f, id)?;
+        }
+        for class in self.classes.iter() {
+            write!(f, ".")?;
+            parse::write_identifier(f, class)?;
+        }
+        for attribute in self.attributes.iter() {
+            write!(f, "{}", attribute)?;
+        }
+        for pseudo_class in self.pseudo_classes.iter() {
+            write!(f, "{}", pseudo_class)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for NthIndex {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.step {
+            0 => return write!(f, "{}", self.offset),
+            1 => write!(f, "n")?,
+            -1 => write!(f, "-n")?,
+            step => write!(f, "{}n", step)?,
+        }
+        match self.offset {
+            0 => Ok(()),
+            offset => write!(f, "{:+}", offset),
+        }
+    }
+}
+
+impl std::fmt::Display for PseudoClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::FirstChild => write!(f, ":first-child"),
+            Self::NthChild(index) => write!(f, ":nth-child({})", index),
+            Self::Not(inner) => write!(f, ":not({})", inner),
+        }
+    }
+}
+
+impl std::fmt::Display for Combinator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Descendant => write!(f, " "),
+            Self::Child => write!(f, " > "),
+            Self::NextSibling => write!(f, " + "),
+            Self::SubsequentSibling => write!(f, " ~ "),
+        }
+    }
+}
+
+impl std::fmt::Display for NodeSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.compounds[0])?;
+        for (combinator, compound) in self.combinators.iter().zip(self.compounds[1..].iter()) {
+            write!(f, "{}{}", combinator, compound)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xml::XmlStore;
+
+    fn parse_nodes(source: &str) -> Vec<NodeAsync> {
+        let store = XmlStore::new();
+        let entry = store.write().unwrap().append_from_source("test".into(), source.into()).unwrap();
+        let nodes = entry.read().unwrap().nodes.to_vec();
+        nodes
+    }
+
+    fn descendants(root: &NodeAsync) -> Vec<NodeAsync> {
+        let mut result: Vec<NodeAsync> = vec![root.clone()];
+        for child in root.read().children.iter() {
+            result.extend(descendants(child));
+        }
+        result
+    }
+
+    fn failure_column(selector: &str) -> usize {
+        match NodeSelector::parse(selector) {
+            Err(Error::SelectorParseFailure(contents)) => contents.column,
+            other => panic!("expected a parse failure for {selector:?}, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn parses_compounds_and_combinators() {
+        let selector = NodeSelector::parse(
+            "Container > Row.button-wrapper#save[kind^=\"pri\"] + *:first-child ~ Column:not(.hidden) Text",
+        )
+        .unwrap();
+
+        let expected = NodeSelector::new()
+            .named("Container".into())
+            .child()
+            .named("Row".into())
+            .with_class("button-wrapper".into())
+            .with_id("save".into())
+            .with_attribute("Default".into(), "kind".into(), AttributeOperator::Prefix("pri".into()))
+            .next_sibling()
+            .first_child()
+            .subsequent_sibling()
+            .named("Column".into())
+            .not(CompoundSelector {
+                classes: vec!["hidden".into()],
+                ..Default::default()
+            })
+            .descendant()
+            .named("Text".into());
+        assert_eq!(selector, expected);
+    }
+
+    #[test]
+    fn parses_nth_child() {
+        let cases = [("2n+1", 2, 1), ("odd", 2, 1), ("even", 2, 0), ("-n+3", -1, 3), ("4", 0, 4), ("n", 1, 0)];
+        for (argument, step, offset) in cases {
+            let selector = NodeSelector::parse(&format!("Row:nth-child({argument})")).unwrap();
+            assert_eq!(
+                selector.compounds()[0].pseudo_classes,
+                vec![PseudoClass::NthChild(NthIndex { step, offset })],
+                "{argument}"
+            );
+        }
+    }
+
+    #[test]
+    fn reports_failure_columns() {
+        assert_eq!(failure_column("Row >> Column"), 6);
+        assert_eq!(failure_column("Row["), 5);
+        assert_eq!(failure_column("Row:nth-child(x)"), 16);
+        assert_eq!(failure_column("Row:hover"), 5);
+        assert_eq!(failure_column(""), 1);
+    }
+
+    #[test]
+    fn display_round_trips() {
+        let selectors = [
+            "Container > Row.button-wrapper",
+            "svg|rect#a-1[data-x~=\"y z\"]",
+            "*:nth-child(2n-1) + Row:not(#x)",
+            "my\\.el ~ \\31 23 Row",
+            "[title=\"say \\\"hi\\\"\"]",
+        ];
+        for source in selectors {
+            let selector = NodeSelector::parse(source).unwrap();
+            let displayed = selector.to_string();
+            assert_eq!(NodeSelector::parse(&displayed).unwrap(), selector, "{source} displayed as {displayed}");
+        }
+    }
+
+    #[test]
+    fn from_node_matches_its_node() {
+        let nodes = parse_nodes(
+            r#"<Container><my.el id="1"><Row class="a">text</Row><Row /></my.el><a-b.c_d /></Container>"#,
+        );
+        for node in descendants(&nodes[0]) {
+            let selector = NodeSelector::from_node(&node).unwrap();
+            assert!(selector.matches(&node), "{node} does not match itself");
+            assert_eq!(selector.to_string(), node.to_string());
+        }
+
+        let row = nodes[0].read().children[0].read().children[1].clone();
+        assert_eq!(row.to_string(), "Container > my\\.el > Row");
+    }
+
+    #[test]
+    fn from_node_refuses_comments_and_processing_instructions() {
+        let nodes = parse_nodes("<Container><!-- note --><?target data?></Container>");
+        for node in nodes[0].read().children.iter() {
+            assert!(matches!(NodeSelector::from_node(node), Err(Error::Unselectable(_))));
+        }
+    }
+}
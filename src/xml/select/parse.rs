@@ -0,0 +1,401 @@
+use std::iter::Peekable;
+use std::str::CharIndices;
+
+use super::{
+    AttributeOperator, AttributePredicate, Combinator, CompoundSelector, NodeSelector, NthIndex, PseudoClass,
+};
+use crate::xml::{Error, SelectorParseFailureContents};
+
+struct Parser<'s> {
+    selector: &'s str,
+    chars: Peekable<CharIndices<'s>>,
+}
+
+impl<'s> Parser<'s> {
+    fn new(selector: &'s str) -> Self {
+        Self {
+            selector,
+            chars: selector.char_indices().peekable(),
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().map(|(_, x)| *x)
+    }
+
+    fn peek_second(&self) -> Option<char> {
+        let mut lookahead = self.chars.clone();
+        lookahead.next();
+        lookahead.next().map(|(_, x)| x)
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        self.chars.next().map(|(_, x)| x)
+    }
+
+    /// 1-based column of the next unread character.
+    fn column(&mut self) -> usize {
+        let offset = match self.chars.peek() {
+            Some((offset, _)) => *offset,
+            None => self.selector.len(),
+        };
+        self.selector[..offset].chars().count() + 1
+    }
+
+    fn fail<T>(&mut self, failure_message: impl Into<String>) -> Result<T, Error> {
+        Err(SelectorParseFailureContents {
+            selector: self.selector.into(),
+            column: self.column(),
+            failure_message: failure_message.into(),
+        }
+        .into())
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), Error> {
+        match self.peek() {
+            Some(found) if found == expected => {
+                self.bump();
+                Ok(())
+            }
+            Some(found) => self.fail(format!("expected '{}', found '{}'", expected, found)),
+            None => self.fail(format!("expected '{}', found end of selector", expected)),
+        }
+    }
+
+    fn skip_whitespace(&mut self) -> bool {
+        let mut skipped = false;
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.bump();
+            skipped = true;
+        }
+        skipped
+    }
+
+    fn at_identifier(&self) -> bool {
+        let mut lookahead = self.chars.clone();
+        match lookahead.next().map(|(_, x)| x) {
+            Some('-') => matches!(lookahead.next().map(|(_, x)| x), Some(x) if is_name_start(x) || x == '-' || x == '\\'),
+            Some(x) => is_name_start(x) || x == '\\',
+            None => false,
+        }
+    }
+
+    fn identifier(&mut self) -> Result<String, Error> {
+        if !self.at_identifier() {
+            return match self.peek() {
+                Some(found) => self.fail(format!("expected identifier, found '{}'", found)),
+                None => self.fail("expected identifier, found end of selector"),
+            };
+        }
+
+        let mut result = String::new();
+        while let Some(next) = self.peek() {
+            if next == '\\' {
+                self.bump();
+                result.push(self.escape()?);
+            } else if is_name(next) {
+                self.bump();
+                result.push(next);
+            } else {
+                break;
+            }
+        }
+        Ok(result)
+    }
+
+    /// Reads the body of a `\` escape: up to six hex digits (plus one trailing
+    /// whitespace) naming a code point, or any other single character verbatim.
+    fn escape(&mut self) -> Result<char, Error> {
+        let mut hex = String::new();
+        while hex.len() < 6 && self.peek().is_some_and(|x| x.is_ascii_hexdigit()) {
+            hex.push(self.bump().unwrap());
+        }
+
+        if hex.is_empty() {
+            return match self.bump() {
+                Some(x) => Ok(x),
+                None => self.fail("unterminated escape"),
+            };
+        }
+
+        if self.peek().is_some_and(char::is_whitespace) {
+            self.bump();
+        }
+        let code_point = u32::from_str_radix(&hex, 16).unwrap();
+        Ok(char::from_u32(code_point).filter(|x| *x != '\0').unwrap_or('\u{FFFD}'))
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        let quote = self.bump().unwrap();
+        let mut result = String::new();
+        loop {
+            match self.bump() {
+                Some(x) if x == quote => return Ok(result),
+                Some('\\') => result.push(self.escape()?),
+                Some(x) => result.push(x),
+                None => return self.fail("unterminated string"),
+            }
+        }
+    }
+
+    fn selector(&mut self) -> Result<NodeSelector, Error> {
+        self.skip_whitespace();
+
+        let mut result = NodeSelector {
+            compounds: vec![self.compound()?],
+            combinators: Vec::new(),
+        };
+
+        loop {
+            let had_whitespace = self.skip_whitespace();
+            let combinator = match self.peek() {
+                None => break,
+                Some('>') => Combinator::Child,
+                Some('+') => Combinator::NextSibling,
+                Some('~') => Combinator::SubsequentSibling,
+                Some(_) if had_whitespace => Combinator::Descendant,
+                Some(found) => return self.fail(format!("unexpected '{}'", found)),
+            };
+            if combinator != Combinator::Descendant {
+                self.bump();
+                self.skip_whitespace();
+            }
+
+            result.combinators.push(combinator);
+            result.compounds.push(self.compound()?);
+        }
+
+        Ok(result)
+    }
+
+    fn compound(&mut self) -> Result<CompoundSelector, Error> {
+        let mut result = CompoundSelector::default();
+        let mut empty = true;
+
+        // type selector, optionally qualified as `ns|Name`, `ns|*` or `*|Name`
+        if self.peek() == Some('*') || self.at_identifier() {
+            let first = match self.peek() {
+                Some('*') => {
+                    self.bump();
+                    None
+                }
+                _ => Some(self.identifier()?),
+            };
+
+            if self.peek() == Some('|') && self.peek_second() != Some('=') {
+                self.bump();
+                result.namespace = first;
+                result.name = match self.peek() {
+                    Some('*') => {
+                        self.bump();
+                        None
+                    }
+                    _ => Some(self.identifier()?),
+                };
+            } else {
+                result.name = first;
+            }
+            empty = false;
+        }
+
+        loop {
+            match self.peek() {
+                Some('#') => {
+                    self.bump();
+                    if result.id.is_some() {
+                        return self.fail("a compound selector can only have one id");
+                    }
+                    result.id = Some(self.identifier()?);
+                }
+                Some('.') => {
+                    self.bump();
+                    result.classes.push(self.identifier()?);
+                }
+                Some('[') => {
+                    self.bump();
+                    result.attributes.push(self.attribute()?);
+                }
+                Some(':') => {
+                    self.bump();
+                    result.pseudo_classes.push(self.pseudo_class()?);
+                }
+                _ => break,
+            }
+            empty = false;
+        }
+
+        if empty {
+            return match self.peek() {
+                Some(found) => self.fail(format!("expected selector, found '{}'", found)),
+                None => self.fail("expected selector, found end of selector"),
+            };
+        }
+        Ok(result)
+    }
+
+    fn attribute(&mut self) -> Result<AttributePredicate, Error> {
+        self.skip_whitespace();
+
+        let mut namespace = String::from("Default");
+        let mut name = self.identifier()?;
+        if self.peek() == Some('|') && self.peek_second() != Some('=') {
+            self.bump();
+            namespace = name;
+            name = self.identifier()?;
+        }
+        self.skip_whitespace();
+
+        let operator: fn(String) -> AttributeOperator = match self.peek() {
+            Some(']') => {
+                self.bump();
+                return Ok(AttributePredicate {
+                    namespace,
+                    name,
+                    operator: AttributeOperator::Exists,
+                });
+            }
+            Some('=') => AttributeOperator::Equals,
+            Some('~') => AttributeOperator::Includes,
+            Some('|') => AttributeOperator::DashMatch,
+            Some('^') => AttributeOperator::Prefix,
+            Some('$') => AttributeOperator::Suffix,
+            Some('*') => AttributeOperator::Substring,
+            Some(found) => return self.fail(format!("unexpected '{}' in attribute selector", found)),
+            None => return self.fail("unterminated attribute selector"),
+        };
+        if self.peek() != Some('=') {
+            self.bump();
+        }
+        self.expect('=')?;
+        self.skip_whitespace();
+
+        let value = match self.peek() {
+            Some('"') | Some('\'') => self.string()?,
+            _ => self.identifier()?,
+        };
+        self.skip_whitespace();
+        self.expect(']')?;
+
+        Ok(AttributePredicate {
+            namespace,
+            name,
+            operator: operator(value),
+        })
+    }
+
+    fn pseudo_class(&mut self) -> Result<PseudoClass, Error> {
+        let column = self.column();
+        let name = self.identifier()?;
+        match name.to_ascii_lowercase().as_str() {
+            "first-child" => Ok(PseudoClass::FirstChild),
+            "nth-child" => {
+                self.expect('(')?;
+                self.skip_whitespace();
+                let index = self.nth_index()?;
+                self.skip_whitespace();
+                self.expect(')')?;
+                Ok(PseudoClass::NthChild(index))
+            }
+            "not" => {
+                self.expect('(')?;
+                self.skip_whitespace();
+                let inner = self.compound()?;
+                self.skip_whitespace();
+                self.expect(')')?;
+                Ok(PseudoClass::Not(Box::new(inner)))
+            }
+            _ => Err(SelectorParseFailureContents {
+                selector: self.selector.into(),
+                column,
+                failure_message: format!("unsupported pseudo-class ':{}'", name),
+            }
+            .into()),
+        }
+    }
+
+    /// `odd`, `even`, `b`, `an`, `an+b` with optional sign on either part.
+    fn nth_index(&mut self) -> Result<NthIndex, Error> {
+        let mut text = String::new();
+        while let Some(next) = self.peek() {
+            if next.is_ascii_alphanumeric() || next == '+' || next == '-' {
+                text.push(next);
+                self.bump();
+            } else if next.is_whitespace() && text.contains(['n', 'N']) {
+                // allow `2n + 1`
+                self.skip_whitespace();
+            } else {
+                break;
+            }
+        }
+
+        let text = text.to_ascii_lowercase();
+        let index = match text.as_str() {
+            "odd" => Some(NthIndex { step: 2, offset: 1 }),
+            "even" => Some(NthIndex { step: 2, offset: 0 }),
+            _ => match text.split_once('n') {
+                None => text.parse().ok().map(|offset| NthIndex { step: 0, offset }),
+                Some((step, offset)) => {
+                    let step = match step {
+                        "" | "+" => Some(1),
+                        "-" => Some(-1),
+                        _ => step.parse().ok(),
+                    };
+                    let offset = match offset {
+                        "" => Some(0),
+                        _ if offset.starts_with('+') || offset.starts_with('-') => offset.parse().ok(),
+                        _ => None,
+                    };
+                    step.zip(offset).map(|(step, offset)| NthIndex { step, offset })
+                }
+            },
+        };
+
+        match index {
+            Some(index) => Ok(index),
+            None => self.fail(format!("invalid :nth-child argument '{}'", text)),
+        }
+    }
+}
+
+fn is_name_start(x: char) -> bool {
+    x.is_ascii_alphabetic() || x == '_' || !x.is_ascii()
+}
+
+fn is_name(x: char) -> bool {
+    is_name_start(x) || x.is_ascii_digit() || x == '-'
+}
+
+/// Writes `identifier` so that the parser reads it back unchanged.
+pub(in crate::xml) fn write_identifier(f: &mut std::fmt::Formatter<'_>, identifier: &str) -> std::fmt::Result {
+    let mut first = true;
+    let identifier = match identifier.strip_prefix('-') {
+        Some(rest) => {
+            write!(f, "-")?;
+            rest
+        }
+        None => identifier,
+    };
+
+    for x in identifier.chars() {
+        if first && x.is_ascii_digit() {
+            write!(f, "\\{:x} ", x as u32)?;
+        } else if is_name(x) {
+            write!(f, "{}", x)?;
+        } else if x.is_whitespace() || x.is_control() {
+            write!(f, "\\{:x} ", x as u32)?;
+        } else {
+            write!(f, "\\{}", x)?;
+        }
+        first = false;
+    }
+    Ok(())
+}
+
+pub(super) fn parse(selector: &str) -> Result<NodeSelector, Error> {
+    let mut parser = Parser::new(selector);
+    let result = parser.selector()?;
+    match parser.peek() {
+        None => Ok(result),
+        Some(found) => parser.fail(format!("unexpected '{}'", found)),
+    }
+}
@@ -0,0 +1,325 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::vec::Vec;
+
+use super::{NodeAsync, NodeKind, XmlNode, GENERATED_ID_PREFIX};
+
+#[derive(Debug, Clone)]
+pub struct SerializeOptions {
+    /// Put every node on its own line, indented by `indent` per level. Text
+    /// is trimmed, and whitespace-only text is dropped.
+    pub pretty: bool,
+    pub indent: String,
+    /// Leave out `id` attributes that were generated on parse.
+    pub strip_generated_ids: bool,
+    /// Emit `<?xml version="1.0"?>` before the first root.
+    pub declaration: bool,
+}
+
+impl Default for SerializeOptions {
+    fn default() -> Self {
+        Self {
+            pretty: false,
+            indent: "  ".into(),
+            strip_generated_ids: false,
+            declaration: false,
+        }
+    }
+}
+
+impl SerializeOptions {
+    pub fn pretty() -> Self {
+        Self {
+            pretty: true,
+            ..Default::default()
+        }
+    }
+}
+
+/// Whether `id` was generated by an `IdStrategy` rather than supplied.
+pub fn is_generated_id(id: &str) -> bool {
+    id.starts_with(GENERATED_ID_PREFIX)
+}
+
+pub fn write_nodes(out: &mut impl Write, nodes: &[NodeAsync], options: &SerializeOptions) -> std::fmt::Result {
+    if options.declaration {
+        write!(out, "<?xml version=\"1.0\"?>")?;
+        if options.pretty {
+            writeln!(out)?;
+        }
+    }
+
+    for (index, node) in nodes.iter().enumerate() {
+        if options.pretty && index > 0 {
+            writeln!(out)?;
+        }
+        write_node(out, node, &BTreeMap::new(), 0, options)?;
+    }
+    Ok(())
+}
+
+fn write_node(
+    out: &mut impl Write,
+    node: &NodeAsync,
+    in_scope: &BTreeMap<String, String>,
+    depth: usize,
+    options: &SerializeOptions,
+) -> std::fmt::Result {
+    let node_guard = node.read();
+
+    if options.pretty {
+        write!(out, "{}", options.indent.repeat(depth))?;
+    }
+
+    match &node_guard.kind {
+        NodeKind::Element => {}
+        NodeKind::Text(content) if options.pretty => return write!(out, "{}", escape(content.trim(), false)),
+        NodeKind::Text(content) => return write!(out, "{}", escape(content, false)),
+        NodeKind::CData(content) => {
+            // `]]>` cannot appear inside a section, so split it across two
+            return write!(out, "<![CDATA[{}]]>", content.replace("]]>", "]]]]><![CDATA[>"));
+        }
+        NodeKind::Comment(content) => return write!(out, "<!--{}-->", content),
+        NodeKind::ProcessingInstruction { target, data } => {
+            return match data {
+                Some(data) => write!(out, "<?{} {}?>", target, data),
+                None => write!(out, "<?{}?>", target),
+            };
+        }
+    }
+
+    let name = qualified_name(node_guard.prefix.as_deref(), &node_guard.name);
+    write!(out, "<{}", name)?;
+
+    let scope = write_namespaces(out, &node_guard, in_scope)?;
+    write_attributes(out, &node_guard, options)?;
+
+    let children: Vec<NodeAsync> = node_guard
+        .children
+        .iter()
+        .filter(|x| !options.pretty || !is_blank_text(&x.read()))
+        .cloned()
+        .collect();
+    drop(node_guard);
+
+    if children.is_empty() {
+        return write!(out, "/>");
+    }
+    write!(out, ">")?;
+
+    if !options.pretty {
+        for child in children.iter() {
+            write_node(out, child, &scope, 0, options)?;
+        }
+    } else if children.len() == 1 && children[0].read().is_text() {
+        // at depth 0 nothing is indented, so lone text stays inline, trimmed
+        write_node(out, &children[0], &scope, 0, options)?;
+    } else {
+        for child in children.iter() {
+            writeln!(out)?;
+            write_node(out, child, &scope, depth + 1, options)?;
+        }
+        write!(out, "\n{}", options.indent.repeat(depth))?;
+    }
+
+    write!(out, "</{}>", name)
+}
+
+/// Declares every namespace that differs from what the parent already has in
+/// scope, and returns the scope the children will see.
+fn write_namespaces(
+    out: &mut impl Write,
+    node: &XmlNode,
+    in_scope: &BTreeMap<String, String>,
+) -> Result<BTreeMap<String, String>, std::fmt::Error> {
+    let mut scope = in_scope.clone();
+    let namespaces = match &node.namespaces {
+        Some(namespaces) => namespaces,
+        None => return Ok(scope),
+    };
+
+    for (key, uri) in namespaces.iter() {
+        // `xml` and `xmlns` are bound implicitly by every parser
+        if key == "xml" || key == "xmlns" {
+            continue;
+        }
+        let inherited = scope.get(key).map(String::as_str).unwrap_or_default();
+        if inherited == uri {
+            continue;
+        }
+
+        match key.as_str() {
+            "Default" => write!(out, " xmlns=\"{}\"", escape(uri, true))?,
+            _ => write!(out, " xmlns:{}=\"{}\"", key, escape(uri, true))?,
+        }
+        scope.insert(key.clone(), uri.clone());
+    }
+
+    Ok(scope)
+}
+
+fn write_attributes(out: &mut impl Write, node: &XmlNode, options: &SerializeOptions) -> std::fmt::Result {
+    // HashMap order is random; sort so output is stable between runs
+    let mut attributes: Vec<(&(String, String), &String)> = node.attributes.iter().collect();
+    attributes.sort();
+
+    for ((namespace, name), value) in attributes {
+        if options.strip_generated_ids && namespace == "Default" && name == "id" && is_generated_id(value) {
+            continue;
+        }
+
+        let prefix = match namespace.as_str() {
+            "Default" => None,
+            namespace => Some(namespace),
+        };
+        write!(out, " {}=\"{}\"", qualified_name(prefix, name), escape(value, true))?;
+    }
+    Ok(())
+}
+
+fn is_blank_text(node: &XmlNode) -> bool {
+    matches!(&node.kind, NodeKind::Text(content) if content.trim().is_empty())
+}
+
+fn qualified_name(prefix: Option<&str>, name: &str) -> String {
+    match prefix {
+        Some(prefix) if !prefix.is_empty() => format!("{}:{}", prefix, name),
+        _ => name.into(),
+    }
+}
+
+fn escape(value: &str, attribute: bool) -> String {
+    let mut result = String::with_capacity(value.len());
+    for x in value.chars() {
+        match x {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' if attribute => result.push_str("&quot;"),
+            '\n' if attribute => result.push_str("&#10;"),
+            '\t' if attribute => result.push_str("&#9;"),
+            '\r' => result.push_str("&#13;"),
+            x => result.push(x),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xml::{parse_nodes, IdStrategy, XmlStore};
+
+    fn nodes(source: &str) -> Vec<NodeAsync> {
+        parse_nodes(&"test".into(), source, &IdStrategy::default()).unwrap().0
+    }
+
+    fn compact(source: &str) -> String {
+        let options = SerializeOptions {
+            strip_generated_ids: true,
+            ..Default::default()
+        };
+        let mut result = String::new();
+        write_nodes(&mut result, &nodes(source), &options).unwrap();
+        result
+    }
+
+    #[test]
+    fn namespaces_are_declared_once_in_scope() {
+        let source = r#"<a:Root xmlns="urn:d" xmlns:a="urn:a"><a:Child><Leaf xmlns:b="urn:b"/></a:Child></a:Root>"#;
+        let result = compact(source);
+        assert_eq!(result, source);
+        assert_eq!(compact(&result), result);
+    }
+
+    #[test]
+    fn redeclared_namespaces_are_written_again() {
+        let source = r#"<Root xmlns="urn:d"><Child xmlns="urn:other"/></Root>"#;
+        assert_eq!(compact(source), source);
+    }
+
+    #[test]
+    fn attributes_and_text_are_escaped() {
+        let source = "<Row label=\"a &lt; b &amp; &quot;c&quot;&#10;\">x &gt; y &amp; z</Row>";
+        let result = compact(source);
+        assert_eq!(result, source);
+
+        let row = nodes(&result).remove(0);
+        assert_eq!(row.read().get_attribute("Default", "label").unwrap(), "a < b & \"c\"\n");
+        assert_eq!(row.read().children[0].read().text(), Some("x > y & z"));
+    }
+
+    #[test]
+    fn cdata_end_marker_is_split_across_sections() {
+        let row = nodes("<Row/>").remove(0);
+        row.append_child(&NodeAsync::from(XmlNode::new(NodeKind::CData("a]]>b".into()), "cdata".into())))
+            .unwrap();
+
+        let result = row.to_xml(&SerializeOptions {
+            strip_generated_ids: true,
+            ..Default::default()
+        });
+        assert_eq!(result, "<Row><![CDATA[a]]]]><![CDATA[>b]]></Row>");
+
+        let text: String =
+            nodes(&result)[0].read().children.iter().filter_map(|x| x.read().text().map(String::from)).collect();
+        assert_eq!(text, "a]]>b");
+    }
+
+    #[test]
+    fn generated_ids_are_stripped_and_supplied_ones_kept() {
+        let roots = nodes(r#"<Row id="save"><Column/></Row>"#);
+        let kept = roots[0].to_xml(&SerializeOptions::default());
+        assert!(kept.contains(GENERATED_ID_PREFIX), "{kept}");
+
+        let stripped = roots[0].to_xml(&SerializeOptions {
+            strip_generated_ids: true,
+            ..Default::default()
+        });
+        assert_eq!(stripped, r#"<Row id="save"><Column/></Row>"#);
+    }
+
+    #[test]
+    fn pretty_output_indents_and_keeps_lone_text_inline() {
+        let source = "<Page>\n  <Row>  one  </Row>\n  <Row><Column/></Row>\n  <!-- end -->\n</Page><Page/>";
+        let options = SerializeOptions {
+            strip_generated_ids: true,
+            declaration: true,
+            ..SerializeOptions::pretty()
+        };
+        let mut result = String::new();
+        write_nodes(&mut result, &nodes(source), &options).unwrap();
+        let expected = [
+            "<?xml version=\"1.0\"?>",
+            "<Page>",
+            "  <Row>one</Row>",
+            "  <Row>",
+            "    <Column/>",
+            "  </Row>",
+            "  <!-- end -->",
+            "</Page>",
+            "<Page/>",
+        ];
+        assert_eq!(result, expected.join("\n"));
+        // the parser already drops whitespace-only text between elements
+        assert_eq!(compact(source), "<Page><Row>  one  </Row><Row><Column/></Row><!-- end --></Page><Page/>");
+    }
+
+    #[test]
+    fn entry_roots_are_elements_with_misc_written_in_place() {
+        let store = XmlStore::new();
+        let source = "<!-- header --><?style sheet?><Page/><!-- between --><Page/>";
+        let entry = store.write().unwrap().append_from_source("test".into(), source.into()).unwrap();
+        let entry_guard = entry.read().unwrap();
+
+        assert_eq!(entry_guard.nodes.len(), 2);
+        assert!(entry_guard.nodes.iter().all(|x| x.read().is_element()));
+        assert_eq!(entry_guard.misc.iter().map(|(before, _)| *before).collect::<Vec<_>>(), [0, 0, 1]);
+
+        let options = SerializeOptions {
+            strip_generated_ids: true,
+            ..Default::default()
+        };
+        assert_eq!(entry_guard.to_xml(&options), source);
+    }
+}
@@ -0,0 +1,169 @@
+use std::sync::{Arc, Mutex};
+
+use peacock_pinion::template::TemplateStore;
+use peacock_pinion::xml::{
+    DuplicateIdPolicy, DuplicateIdWarning, IdStrategy, NodeAsync, NodeChange, Patch, Scope, StoreEntry, XmlStore,
+};
+
+#[test]
+fn edited_dependencies_make_entries_stale() {
+    let template_store = TemplateStore::new();
+    let dom_store = XmlStore::new();
+    let top = {
+        let template_guard = template_store.read().unwrap();
+        template_guard.append_raw("part".into(), "<Row>{{ label }}</Row>".into()).unwrap();
+        template_guard.append_raw("middle".into(), "{% include 'part' %}".into()).unwrap();
+        template_guard
+            .append_raw("top".into(), "<Column>{% include 'middle' %}</Column>".into())
+            .unwrap()
+    };
+    let entry = dom_store
+        .write()
+        .unwrap()
+        .append_from_rendered_template("page".into(), top, minijinja::context! { label => "a" })
+        .unwrap();
+
+    {
+        let entry_guard = entry.read().unwrap();
+        assert!(!entry_guard.is_stale().unwrap());
+        assert!(entry_guard.depends_on(&"part".into()).unwrap());
+        assert!(entry_guard.depends_on(&"middle".into()).unwrap());
+        assert!(!entry_guard.depends_on(&"other".into()).unwrap());
+    }
+
+    template_store
+        .read()
+        .unwrap()
+        .replace(&"part".into(), "<Row class=\"b\">{{ label }}</Row>".into())
+        .unwrap();
+    assert!(entry.read().unwrap().is_stale().unwrap());
+
+    let rerendered = dom_store.read().unwrap().rerender_dependents(&"part".into());
+    assert_eq!(rerendered.len(), 1);
+    assert_eq!(rerendered[0].0, "page");
+    assert_eq!(rerendered[0].1.as_ref().unwrap().len(), 1);
+
+    let entry_guard = entry.read().unwrap();
+    assert!(!entry_guard.is_stale().unwrap());
+    assert_eq!(entry_guard.source, "<Column><Row class=\"b\">a</Row></Column>");
+}
+
+/// An entry's index and its duplicate ids.
+type Warning = (String, Vec<String>);
+
+#[test]
+fn duplicate_ids_are_reported_under_warn() {
+    let warnings: Arc<Mutex<Vec<Warning>>> = Arc::new(Mutex::new(Vec::new()));
+    let dom_store = XmlStore::new();
+    {
+        let warnings = warnings.clone();
+        dom_store.write().unwrap().on_duplicate_ids = Some(DuplicateIdWarning::new(move |index, ids| {
+            warnings.lock().unwrap().push((index.clone(), ids.to_vec()));
+        }));
+    }
+
+    let source = r#"<Row id="a"><Column id="b"/><Column id="b"/><Column id="a"/></Row>"#;
+    dom_store.write().unwrap().append_from_source("static".into(), source.into()).unwrap();
+    assert_eq!(*warnings.lock().unwrap(), [("static".to_string(), vec!["a".to_string(), "b".to_string()])]);
+
+    let template_store = TemplateStore::new();
+    let template = template_store
+        .read()
+        .unwrap()
+        .append_raw("list".into(), r#"<Row>{% for x in ids %}<Column id="{{ x }}"/>{% endfor %}</Row>"#.into())
+        .unwrap();
+    let entry = dom_store
+        .write()
+        .unwrap()
+        .append_from_rendered_template("list".into(), template, minijinja::context! { ids => ["x", "y"] })
+        .unwrap();
+    assert_eq!(warnings.lock().unwrap().len(), 1);
+
+    StoreEntry::rerender(&entry, minijinja::context! { ids => ["x", "y", "x"] }).unwrap();
+    assert_eq!(warnings.lock().unwrap()[1], ("list".to_string(), vec!["x".to_string()]));
+}
+
+fn ids(node: &NodeAsync) -> Vec<String> {
+    let node_guard = node.try_read().unwrap();
+    let mut result: Vec<String> = node_guard.get_attribute("Default", "id").into_iter().collect();
+    for child in node_guard.children.iter() {
+        result.extend(ids(child));
+    }
+    result
+}
+
+#[test]
+fn rerender_gives_kept_nodes_the_ids_of_a_fresh_parse() {
+    let template_store = TemplateStore::new();
+    let template = template_store
+        .read()
+        .unwrap()
+        .append_raw("list".into(), "<Row>{% for x in items %}<I>{{ x }}</I>{% endfor %}</Row>".into())
+        .unwrap();
+
+    for strategy in [IdStrategy::ContentHash, IdStrategy::Path] {
+        let dom_store = XmlStore::new();
+        {
+            let mut dom_guard = dom_store.write().unwrap();
+            dom_guard.id_strategy = strategy.clone();
+            dom_guard.duplicate_id_policy = DuplicateIdPolicy::Error;
+        }
+        let entry = dom_store
+            .write()
+            .unwrap()
+            .append_from_rendered_template("list".into(), template.clone(), minijinja::context! { items => ["a", "b"] })
+            .unwrap();
+        let patches = StoreEntry::rerender(&entry, minijinja::context! { items => ["x", "a", "b"] }).unwrap();
+
+        let fresh = dom_store
+            .write()
+            .unwrap()
+            .append_from_source("fresh".into(), "<Row><I>x</I><I>a</I><I>b</I></Row>".into())
+            .unwrap();
+        let entry_guard = entry.read().unwrap();
+        let fresh_guard = fresh.read().unwrap();
+        assert_eq!(ids(&entry_guard.nodes[0]), ids(&fresh_guard.nodes[0]), "{strategy:?}");
+        assert!(entry_guard.duplicate_ids().is_empty(), "{strategy:?}");
+
+        // unkeyed nodes pair up by position, so paths stay put while the row,
+        // both kept items and their text all hash differently
+        let updated_ids = patches.iter().filter(|x| {
+            matches!(x, Patch::Update { change: NodeChange::Attribute { key, .. }, .. } if key.1 == "id")
+        });
+        let expected = match strategy {
+            IdStrategy::ContentHash => 5,
+            _ => 0,
+        };
+        assert_eq!(updated_ids.count(), expected, "{strategy:?}");
+    }
+}
+
+#[test]
+fn observers_can_read_the_entry_during_a_rerender() {
+    let template_store = TemplateStore::new();
+    let template = template_store
+        .read()
+        .unwrap()
+        .append_raw("row".into(), r#"<Row id="row" class="{{ class }}"/>"#.into())
+        .unwrap();
+    let dom_store = XmlStore::new();
+    let entry = dom_store
+        .write()
+        .unwrap()
+        .append_from_rendered_template("row".into(), template, minijinja::context! { class => "a" })
+        .unwrap();
+
+    let seen: Arc<Mutex<Vec<bool>>> = Arc::new(Mutex::new(Vec::new()));
+    {
+        let (seen, weak_entry) = (seen.clone(), Arc::downgrade(&entry));
+        let root = entry.read().unwrap().nodes[0].clone();
+        root.subscribe(Scope::Subtree, move |_| {
+            let entry = weak_entry.upgrade().unwrap();
+            let found = entry.try_read().map(|x| x.get_element_by_id("row").is_some());
+            seen.lock().unwrap().push(found.unwrap_or(false));
+        });
+    }
+
+    StoreEntry::rerender(&entry, minijinja::context! { class => "b" }).unwrap();
+    assert_eq!(*seen.lock().unwrap(), [true]);
+}
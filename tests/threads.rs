@@ -0,0 +1,104 @@
+use std::sync::{Arc, Barrier};
+use std::thread;
+
+use peacock_pinion::template::TemplateStore;
+use peacock_pinion::xml::select::NodeSelector;
+use peacock_pinion::xml::{Error, NodeAsync, XmlStore};
+
+const THREADS: usize = 8;
+
+fn text_of(node: &NodeAsync) -> String {
+    let child = node.try_read().unwrap().children[0].clone();
+    let child_guard = child.try_read().unwrap();
+    child_guard.text().unwrap().to_string()
+}
+
+#[test]
+fn renders_and_appends_from_several_threads_at_once() {
+    let template_store = TemplateStore::new();
+    let list_template = template_store
+        .read()
+        .unwrap()
+        .append_raw(
+            "list".into(),
+            "<Column>{% for i in range(count) %}<Row class=\"item\">{{ i }}</Row>{% endfor %}</Column>".into(),
+        )
+        .unwrap();
+    let selector = NodeSelector::parse("Column > Row.item").unwrap();
+    let dom_store = XmlStore::new();
+    let barrier = Arc::new(Barrier::new(THREADS));
+
+    let workers: Vec<thread::JoinHandle<Vec<String>>> = (0..THREADS)
+        .map(|worker| {
+            let list_template = list_template.clone();
+            let dom_store = dom_store.clone();
+            let selector = selector.clone();
+            let barrier = barrier.clone();
+
+            thread::spawn(move || {
+                // every thread is running before any of them renders
+                barrier.wait();
+                let source = list_template
+                    .read()
+                    .unwrap()
+                    .render(minijinja::context! { count => worker + 1 })
+                    .unwrap();
+
+                // every thread parses into the one store, racing the others
+                let entry = dom_store.write().unwrap().append_from_source(format!("list-{worker}"), source).unwrap();
+                let root = entry.read().unwrap().nodes[0].clone();
+                selector.apply(root).iter().map(text_of).collect()
+            })
+        })
+        .collect();
+
+    for (worker, handle) in workers.into_iter().enumerate() {
+        let expected: Vec<String> = (0..=worker).map(|x| x.to_string()).collect();
+        assert_eq!(handle.join().unwrap(), expected);
+    }
+    assert_eq!(dom_store.read().unwrap().indices.read().unwrap().len(), THREADS);
+
+    // query the stored entries from every thread under read locks only
+    let workers: Vec<thread::JoinHandle<usize>> = (0..THREADS)
+        .map(|worker| {
+            let dom_store = dom_store.clone();
+            let selector = selector.clone();
+            let barrier = barrier.clone();
+
+            thread::spawn(move || {
+                barrier.wait();
+                let entry = dom_store.read().unwrap().get(&format!("list-{worker}")).unwrap();
+                let root = entry.read().unwrap().nodes[0].clone();
+                selector.apply(root).len()
+            })
+        })
+        .collect();
+
+    for (worker, handle) in workers.into_iter().enumerate() {
+        assert_eq!(handle.join().unwrap(), worker + 1);
+    }
+}
+
+#[test]
+fn poisoned_locks_are_reported() {
+    let dom_store = XmlStore::new();
+    let entry = dom_store.write().unwrap().append_from_source("page".into(), "<Row/>".into()).unwrap();
+    let root = entry.read().unwrap().nodes[0].clone();
+
+    let panicking = root.clone();
+    let _ = thread::spawn(move || {
+        let _node_guard = panicking.try_write().unwrap();
+        panic!("poisons the node lock");
+    })
+    .join();
+    assert!(matches!(root.try_read(), Err(Error::PoisonedLock)));
+    assert!(matches!(root.try_write(), Err(Error::PoisonedLock)));
+
+    let indices = dom_store.read().unwrap().indices.clone();
+    let _ = thread::spawn(move || {
+        let _indices_guard = indices.write().unwrap();
+        panic!("poisons the store's indices");
+    })
+    .join();
+    assert!(matches!(dom_store.read().unwrap().has(&"page".into()), Err(Error::PoisonedLock)));
+}